@@ -0,0 +1,431 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+use crate::app::{Channel, ChannelRegistry, ChannelTransition, CustomEvent, HashMap, PresenceData, PresenceUser, Pusher, RemovedMember, ServerEvent, Deserialize, Serialize};
+
+/// How often a node tells the other nodes which channels and members it holds.
+const MEMBERSHIP_INTERVAL: Duration = Duration::from_secs(5);
+/// Nodes that have not announced their membership for this long are considered gone.
+const MEMBERSHIP_EXPIRY: Duration = Duration::from_secs(15);
+
+/// Carries messages between the nodes serving the same apps, so that an event published
+/// on one node reaches the sockets connected to every other node. Messages are opaque
+/// strings; a node receives everything published for the apps it subscribed to, its own
+/// messages included.
+#[async_trait]
+pub trait Adapter: std::fmt::Debug + Send + Sync {
+    async fn publish(&self, app_id: u32, message: String) -> Result<(), crate::Error>;
+    async fn subscribe(&self, app_id: u32) -> Result<mpsc::UnboundedReceiver<String>, crate::Error>;
+}
+
+/// Connects nodes living in the same process, mostly useful in tests and embedders
+/// running several servers side by side. Clones share the same bus.
+#[derive(Clone, Debug)]
+pub struct MemoryAdapter {
+    bus: broadcast::Sender<(u32, String)>,
+}
+
+impl MemoryAdapter {
+    pub fn new() -> Self {
+        MemoryAdapter { bus: broadcast::channel(1024).0 }
+    }
+}
+
+impl Default for MemoryAdapter {
+    fn default() -> Self {
+        MemoryAdapter::new()
+    }
+}
+
+#[async_trait]
+impl Adapter for MemoryAdapter {
+    async fn publish(&self, app_id: u32, message: String) -> Result<(), crate::Error> {
+        // Nobody listening is not an error, the node may simply be alone.
+        let _ = self.bus.send((app_id, message));
+        Ok(())
+    }
+
+    async fn subscribe(&self, app_id: u32) -> Result<mpsc::UnboundedReceiver<String>, crate::Error> {
+        let mut bus = self.bus.subscribe();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                match bus.recv().await {
+                    Ok((id, message)) if id == app_id => {
+                        if sender.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => eprintln!("memory adapter dropped {} messages", skipped),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+/// What a node relays to the others.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ClusterMessage {
+    ChannelEvent { event: CustomEvent, except: Option<String> },
+    UserEvent { user_id: String, event: CustomEvent },
+    TerminateUser { user_id: String },
+    MemberAdded { channel: String, member: PresenceUser },
+    MemberRemoved { channel: String, user_id: String },
+    ChannelOccupied { channel: String },
+    ChannelVacated { channel: String },
+    Membership(NodeMembership),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    node_id: String,
+    #[serde(flatten)]
+    message: ClusterMessage,
+}
+
+/// The connections and occupied channels of one node.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct NodeMembership {
+    pub connections: usize,
+    pub channels: HashMap<String, ChannelMembership>,
+}
+
+impl NodeMembership {
+    fn of(pusher: &Pusher) -> Self {
+        NodeMembership {
+            connections: pusher.connections_count(),
+            channels: ChannelMembership::occupied(&pusher.channels),
+        }
+    }
+
+    fn has_member(&self, channel: &str, user_id: &str) -> bool {
+        self.channels.get(channel)
+            .and_then(|channel| channel.users.as_ref())
+            .is_some_and(|users| users.contains_key(user_id))
+    }
+
+    /// Marks the channel occupied, its counts follow with the next announcement.
+    fn occupy(&mut self, channel: &str) -> &mut ChannelMembership {
+        let membership = self.channels.entry(channel.to_owned()).or_default();
+        membership.subscriptions = membership.subscriptions.max(1);
+        membership
+    }
+}
+
+/// Subscriptions and presence members of a channel, on one node or merged across nodes.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ChannelMembership {
+    pub subscriptions: usize,
+    /// Members with their user info, `None` unless this is a presence channel.
+    pub users: Option<HashMap<String, serde_json::Value>>,
+}
+
+impl ChannelMembership {
+    /// The channels of the registry that have subscribers.
+    fn occupied(channels: &ChannelRegistry) -> HashMap<String, ChannelMembership> {
+        let mut occupied = HashMap::default();
+        channels.for_each(|name, channel| if !channel.is_empty() {
+            occupied.insert(name.to_owned(), ChannelMembership::from(channel));
+        });
+        occupied
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.subscriptions == 0
+    }
+
+    pub(crate) fn users_count(&self) -> Option<usize> {
+        self.users.as_ref().map(HashMap::len)
+    }
+
+    /// Users connected to several nodes are counted once.
+    fn merge(&mut self, other: &ChannelMembership) {
+        self.subscriptions += other.subscriptions;
+        if let Some(users) = &other.users {
+            self.users.get_or_insert_with(HashMap::default).extend(users.iter().map(|(id, info)| (id.to_owned(), info.clone())));
+        }
+    }
+}
+
+impl From<&Channel> for ChannelMembership {
+    fn from(channel: &Channel) -> Self {
+        ChannelMembership {
+            subscriptions: channel.subscriptions_count(),
+            users: channel.members().map(|members| members.iter().map(|(id, member)| (id.to_owned(), member.info.clone())).collect()),
+        }
+    }
+}
+
+/// The view an app has of the other nodes. Without an adapter the node is alone and
+/// every aggregate is simply the local state. Besides the periodic announcements, nodes
+/// relay their member and occupancy changes as they happen, so that only the first node
+/// a user or a channel shows up on reports it, and only the last one reports it gone.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Cluster {
+    node_id: String,
+    outbound: Option<mpsc::UnboundedSender<String>>,
+    peers: Arc<Mutex<HashMap<String, (NodeMembership, Instant)>>>,
+}
+
+impl Cluster {
+    /// Subscribes the app to the adapter and starts relaying: outgoing messages, incoming
+    /// messages delivered to the local sockets and the periodic membership announcement.
+    pub(crate) async fn join(pusher: &Pusher, adapter: Arc<dyn Adapter>) -> Result<Cluster, crate::Error> {
+        let app_id = pusher.id;
+        let mut inbound = adapter.subscribe(app_id).await?;
+        let (outbound, mut queue) = mpsc::unbounded_channel::<String>();
+        let cluster = Cluster {
+            node_id: format!("{:016x}", rand::random::<u64>()),
+            outbound: Some(outbound),
+            peers: Arc::default(),
+        };
+        let mut pusher = pusher.clone();
+        pusher.cluster = cluster.clone();
+
+        tokio::spawn(async move {
+            while let Some(message) = queue.recv().await {
+                if let Err(err) = adapter.publish(app_id, message).await {
+                    eprintln!("cluster publish failed for app {}: {}", app_id, err);
+                }
+            }
+        });
+
+        let mut transitions = pusher.channels.transitions();
+        let relay = cluster.clone();
+        tokio::spawn(async move {
+            while let Some(transition) = transitions.recv().await {
+                relay.broadcast(match transition {
+                    ChannelTransition::Occupied(channel) => ClusterMessage::ChannelOccupied { channel },
+                    ChannelTransition::Vacated(channel) => ClusterMessage::ChannelVacated { channel },
+                });
+            }
+        });
+
+        let receiver = pusher.clone();
+        tokio::spawn(async move {
+            while let Some(message) = inbound.recv().await {
+                match serde_json::from_str::<Envelope>(&message) {
+                    Ok(envelope) if envelope.node_id == receiver.cluster.node_id => {}
+                    Ok(envelope) => receiver.cluster.deliver(&receiver, envelope).await,
+                    Err(err) => eprintln!("invalid cluster message for app {}: {}", app_id, err),
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MEMBERSHIP_INTERVAL);
+            loop {
+                interval.tick().await;
+                pusher.cluster.announce(&pusher).await;
+            }
+        });
+
+        Ok(cluster)
+    }
+
+    /// Sends the message to the other nodes, without waiting for the adapter.
+    pub(crate) fn broadcast(&self, message: ClusterMessage) {
+        let Some(outbound) = &self.outbound else { return };
+        let envelope = Envelope { node_id: self.node_id.to_owned(), message };
+        match serde_json::to_string(&envelope) {
+            Ok(message) => {
+                let _ = outbound.send(message);
+            }
+            Err(err) => eprintln!("cannot encode cluster message: {}", err),
+        }
+    }
+
+    /// Tells the other nodes which channels and members this node holds.
+    pub(crate) async fn announce(&self, pusher: &Pusher) {
+        if self.outbound.is_some() {
+            self.broadcast(ClusterMessage::Membership(NodeMembership::of(pusher)));
+        }
+    }
+
+    async fn deliver(&self, pusher: &Pusher, envelope: Envelope) {
+        match envelope.message {
+            ClusterMessage::ChannelEvent { event, except } => pusher.publish_local(event, except.as_deref()).await,
+            ClusterMessage::UserEvent { user_id, event } => pusher.publish_to_user(&user_id, ServerEvent::ChannelEvent(event)).await,
+            ClusterMessage::TerminateUser { user_id } => pusher.terminate_user_connections(&user_id).await,
+            // Members also connected to another node were already announced, or are still here.
+            ClusterMessage::MemberAdded { channel, member } => {
+                self.update_peer(&envelope.node_id, |peer| {
+                    peer.occupy(&channel).users.get_or_insert_with(HashMap::default).insert(member.id.to_owned(), member.info.clone());
+                });
+                if !self.any_peer(Some(&envelope.node_id), |peer| peer.has_member(&channel, &member.id)) {
+                    let user_id = member.id.to_owned();
+                    let event = ServerEvent::MemberAdded { channel: channel.to_owned(), data: member };
+                    Cluster::deliver_to_others(pusher, &channel, &user_id, event).await;
+                }
+            }
+            ClusterMessage::MemberRemoved { channel, user_id } => {
+                self.update_peer(&envelope.node_id, |peer| {
+                    if let Some(users) = peer.channels.get_mut(&channel).and_then(|channel| channel.users.as_mut()) {
+                        users.remove(&user_id);
+                    }
+                });
+                if !self.has_member_elsewhere(&channel, &user_id) {
+                    let event = ServerEvent::MemberRemoved { channel: channel.to_owned(), data: RemovedMember { id: user_id.to_owned() } };
+                    Cluster::deliver_to_others(pusher, &channel, &user_id, event).await;
+                }
+            }
+            ClusterMessage::ChannelOccupied { channel } => self.update_peer(&envelope.node_id, |peer| {
+                peer.occupy(&channel);
+            }),
+            ClusterMessage::ChannelVacated { channel } => self.update_peer(&envelope.node_id, |peer| {
+                peer.channels.remove(&channel);
+            }),
+            ClusterMessage::Membership(membership) => {
+                self.peers.lock().unwrap_or_else(PoisonError::into_inner).insert(envelope.node_id, (membership, Instant::now()));
+            }
+        }
+    }
+
+    /// Applies a change relayed by another node to the membership it last announced.
+    fn update_peer(&self, node_id: &str, update: impl FnOnce(&mut NodeMembership)) {
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        let (membership, seen) = peers.entry(node_id.to_owned()).or_insert_with(|| (NodeMembership::default(), Instant::now()));
+        update(membership);
+        *seen = Instant::now();
+    }
+
+    /// Publishes a member change unless the user also has a socket subscribed on this node.
+    async fn deliver_to_others(pusher: &Pusher, channel: &str, user_id: &str, event: ServerEvent) {
+        let delivery = pusher.channels.inspect(channel, |entry| entry
+            .filter(|entry| !entry.has_member(user_id))
+            .map(|entry| entry.delivery(&event, None)));
+        if let Some(delivery) = delivery {
+            pusher.metrics.messages_dropped(delivery.send());
+        }
+    }
+
+    /// The memberships of the nodes heard from recently, by node id. Callers read what
+    /// they need in place rather than copying every channel of every node.
+    fn live_peers(&self) -> MutexGuard<'_, HashMap<String, (NodeMembership, Instant)>> {
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        peers.retain(|_, (_, seen)| seen.elapsed() < MEMBERSHIP_EXPIRY);
+        peers
+    }
+
+    fn any_peer(&self, except: Option<&str>, predicate: impl Fn(&NodeMembership) -> bool) -> bool {
+        self.live_peers().iter()
+            .filter(|(node_id, _)| Some(node_id.as_str()) != except)
+            .any(|(_, (membership, _))| predicate(membership))
+    }
+
+    /// Whether the user has a socket subscribed to the channel on another node.
+    pub(crate) fn has_member_elsewhere(&self, channel: &str, user_id: &str) -> bool {
+        self.any_peer(None, |peer| peer.has_member(channel, user_id))
+    }
+
+    /// Whether the channel has subscribers on another node.
+    pub(crate) fn is_occupied_elsewhere(&self, channel: &str) -> bool {
+        self.any_peer(None, |peer| peer.channels.get(channel).is_some_and(|channel| !channel.is_empty()))
+    }
+
+    pub(crate) fn connections(&self, local: usize) -> usize {
+        local + self.live_peers().values().map(|(peer, _)| peer.connections).sum::<usize>()
+    }
+
+    /// The channel as seen by the whole cluster.
+    pub(crate) fn channel(&self, name: &str, local: Option<&Channel>) -> ChannelMembership {
+        let mut membership = ChannelMembership::from(local.unwrap_or(&Channel::from(name.to_owned())));
+        for (peer, _) in self.live_peers().values() {
+            if let Some(channel) = peer.channels.get(name) {
+                membership.merge(channel);
+            }
+        }
+        membership
+    }
+
+    /// Every channel occupied on at least one node.
+    pub(crate) fn channels(&self, local: &ChannelRegistry) -> HashMap<String, ChannelMembership> {
+        let mut channels = ChannelMembership::occupied(local);
+        for (peer, _) in self.live_peers().values() {
+            for (name, channel) in peer.channels.iter() {
+                channels.entry(name.to_owned()).or_default().merge(channel);
+            }
+        }
+        channels
+    }
+
+    /// Presence data listing the members connected to any node.
+    pub(crate) fn presence_data(&self, name: &str, local: &Channel) -> Option<PresenceData> {
+        self.channel(name, Some(local)).users.map(PresenceData::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::app::{EventRequestBody, MemoryAdapter, OutboxReceiver, Pusher, json};
+    use crate::app::testing::{node, subscribe, until};
+
+    async fn recv(receiver: &mut OutboxReceiver) -> serde_json::Value {
+        let event = tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await.expect("event").unwrap();
+        serde_json::from_str(&event).unwrap()
+    }
+
+    async fn publish(pusher: &Pusher, request: serde_json::Value) {
+        let request: EventRequestBody = serde_json::from_value(request).unwrap();
+        request.payload_publish(pusher.clone()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn events_published_on_one_node_reach_every_node() {
+        let adapter = MemoryAdapter::new();
+        let (first, second) = (node(&adapter).await, node(&adapter).await);
+        let mut local = subscribe(&first, "orders", "1.1", None).await;
+        let mut remote = subscribe(&second, "orders", "2.2", None).await;
+        let mut excluded = subscribe(&second, "orders", "2.3", None).await;
+
+        publish(&first, json!({"name": "created", "channel": "orders", "data": "{}", "socket_id": "2.3"})).await;
+        let sent = recv(&mut local).await;
+        assert_eq!(sent["event"], "created");
+        assert_eq!(recv(&mut remote).await, sent);
+
+        // Relayed after the first event, so anything else that event caused comes before it.
+        publish(&second, json!({"name": "shipped", "channel": "orders", "data": "{}"})).await;
+        assert_eq!(recv(&mut excluded).await["event"], "shipped");
+        assert_eq!(recv(&mut local).await["event"], "shipped", "a node must not deliver its own messages twice");
+    }
+
+    #[tokio::test]
+    async fn counts_are_aggregated_across_nodes() {
+        let adapter = MemoryAdapter::new();
+        let (first, second) = (node(&adapter).await, node(&adapter).await);
+        let _a = subscribe(&first, "presence-room", "1.1", Some("alice")).await;
+        let _b = subscribe(&second, "presence-room", "2.1", Some("alice")).await;
+        let _c = subscribe(&second, "presence-room", "2.2", Some("bob")).await;
+        let _d = subscribe(&second, "news", "2.2", None).await;
+
+        second.cluster.announce(&second).await;
+        let room = || first.channels.inspect("presence-room", |channel| first.cluster.channel("presence-room", channel));
+        until(|| room().subscriptions == 3).await;
+
+        assert_eq!(room().users_count(), Some(2));
+        let occupied = first.cluster.channels(&first.channels);
+        assert_eq!(occupied.len(), 2);
+        assert_eq!(occupied["news"].subscriptions, 1);
+        assert_eq!(occupied["news"].users_count(), None);
+    }
+
+    #[tokio::test]
+    async fn occupancy_is_relayed_before_the_next_announcement() {
+        let adapter = MemoryAdapter::new();
+        let (first, second) = (node(&adapter).await, node(&adapter).await);
+
+        let _receiver = subscribe(&second, "news", "2.1", None).await;
+        until(|| first.cluster.is_occupied_elsewhere("news")).await;
+        assert!(!second.cluster.is_occupied_elsewhere("news"));
+
+        second.channels.update("news", |channel| channel.remove_subscription("2.1"));
+        until(|| !first.cluster.is_occupied_elsewhere("news")).await;
+    }
+}
@@ -1,19 +1,44 @@
-use futures::future;
+use std::hash::BuildHasher;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use hashbrown::hash_map::DefaultHashBuilder;
+use serde::ser::SerializeMap;
 use tokio::sync::mpsc;
-use crate::app::{ServerEvent, HashMap, Serialize};
+use tokio::time::Instant;
+use crate::app::{CustomEvent, Frame, Outbox, ServerEvent, HashMap, Deserialize, Serialize, CustomError};
+
+/// How long cache channels replay their last event, as on Pusher.
+pub(crate) const CACHE_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// How often events older than [`CACHE_TTL`] are swept out of the registry.
+pub(crate) const CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of separately locked parts of a [`ChannelRegistry`].
+const SHARDS: usize = 32;
 
 #[repr(C)]
 #[derive(Clone, Debug, Serialize)]
 pub(crate) struct Subscription {
     #[serde(skip)]
-    pub sender: mpsc::Sender<ServerEvent>,
+    pub sender: Outbox,
     pub data: Option<serde_json::Value>,
     pub user_id: Option<String>,
 }
 
-impl Subscription {
-    pub async fn publish(&self, msg: ServerEvent) -> Result<(), String> {
-        self.sender.send(msg).await.map_err(|err| format!("{}", err))
+/// A frame together with the sockets it is for, taken out of the registry so that
+/// sending never holds a lock.
+#[must_use]
+#[derive(Debug)]
+pub(crate) struct Delivery {
+    frame: Frame,
+    senders: Vec<Outbox>,
+}
+
+impl Delivery {
+    /// Queues the frame for every socket without waiting on any of them, returning how many
+    /// frames were dropped.
+    pub(crate) fn send(self) -> usize {
+        self.senders.iter().filter(|sender| !sender.push(self.frame.clone())).count()
     }
 }
 
@@ -26,17 +51,26 @@ pub(crate) enum Channel {
     Private {
         subscriptions: HashMap<String, Subscription>,
     },
+    /// `private-encrypted-` channels, whose payloads only the subscribers can decrypt.
+    Encrypted {
+        subscriptions: HashMap<String, Subscription>,
+    },
     Presence {
         subscriptions: HashMap<String, Subscription>,
-        users: HashMap<String, serde_json::Value>,
+        users: HashMap<String, PresenceMember>,
     },
 }
 
 impl Channel {
-    pub(crate) async fn publish(&self, event: ServerEvent) -> Result<(), String> {
-        let messages = self.subscriptions().values().map(|sub| sub.publish(event.clone()));
-        future::join_all(messages).await;
-        Ok(())
+    /// Encodes the event for every subscriber except `except`.
+    pub(crate) fn delivery(&self, event: &ServerEvent, except: Option<&str>) -> Delivery {
+        Delivery {
+            frame: Frame::from(event),
+            senders: self.subscriptions().iter()
+                .filter(|(id, _)| Some(id.as_str()) != except)
+                .map(|(_, sub)| sub.sender.clone())
+                .collect(),
+        }
     }
 
     pub(crate) fn is_empty(&self) -> bool {
@@ -47,34 +81,94 @@ impl Channel {
         self.subscriptions().len()
     }
 
-    pub(crate) fn add_subscription(&mut self, socket_id: &str, subscription: Subscription) {
+    /// Adds the socket to the channel. On presence channels the returned user is
+    /// the member that joined, only set for the first socket of that user.
+    pub(crate) fn add_subscription(&mut self, socket_id: &str, subscription: Subscription) -> Option<PresenceUser> {
+        if self.subscriptions().contains_key(socket_id) {
+            return None;
+        }
+
+        let joined = match (&mut *self, &subscription.user_id) {
+            (Channel::Presence { users, .. }, Some(user_id)) => {
+                let member = users.entry(user_id.to_owned()).or_insert_with(|| PresenceMember {
+                    info: subscription.data.clone().unwrap_or_default(),
+                    connections: 0,
+                });
+                member.connections += 1;
+                (member.connections == 1).then(|| PresenceUser { id: user_id.to_owned(), info: member.info.clone() })
+            }
+            _ => None,
+        };
+
         self.subscriptions_mut().insert(socket_id.to_owned(), subscription);
+        joined
+    }
+
+    /// Removes the socket from the channel. On presence channels the returned member
+    /// is the user that left, only set once their last socket is gone.
+    pub(crate) fn remove_subscription(&mut self, socket_id: &str) -> Option<RemovedMember> {
+        let subscription = self.subscriptions_mut().remove(socket_id)?;
+
+        match (self, subscription.user_id) {
+            (Channel::Presence { users, .. }, Some(user_id)) => {
+                let member = users.get_mut(&user_id)?;
+                member.connections = member.connections.saturating_sub(1);
+                if member.connections > 0 {
+                    return None;
+                }
+                users.remove(&user_id);
+                Some(RemovedMember { id: user_id })
+            }
+            _ => None,
+        }
+    }
+
+    /// Members of a presence channel, `None` for other channels.
+    pub(crate) fn members(&self) -> Option<&HashMap<String, PresenceMember>> {
+        match self {
+            Channel::Presence { users, .. } => Some(users),
+            _ => None,
+        }
+    }
+    pub(crate) fn has_member(&self, user_id: &str) -> bool {
+        self.members().is_some_and(|members| members.contains_key(user_id))
+    }
+    pub(crate) fn requires_authentication(&self) -> bool {
+        !matches!(self, Channel::Public { .. })
+    }
+
+    pub(crate) fn accepts_client_events(&self) -> bool {
+        matches!(self, Channel::Private { .. } | Channel::Presence { .. })
     }
 
-    pub(crate) fn remove_subscription(&mut self, socket_id: &str) {
-        self.subscriptions_mut().remove(socket_id);
+    pub(crate) fn is_encrypted(&self) -> bool {
+        matches!(self, Channel::Encrypted { .. })
     }
 
+    #[allow(dead_code)]
     pub(crate) fn users_count(&self) -> Option<usize> {
         match self {
             Channel::Public { .. } => None,
             Channel::Private { .. } => None,
+            Channel::Encrypted { .. } => None,
             Channel::Presence { users, .. } => Some(users.len()),
         }
     }
 
     pub(crate) fn subscriptions(&self) -> &HashMap<String, Subscription> {
         match self {
-            Channel::Public { subscriptions } => subscriptions,
-            Channel::Private { subscriptions } => subscriptions,
+            Channel::Public { subscriptions, .. } => subscriptions,
+            Channel::Private { subscriptions, .. } => subscriptions,
+            Channel::Encrypted { subscriptions, .. } => subscriptions,
             Channel::Presence { subscriptions, .. } => subscriptions,
         }
     }
 
     pub(crate) fn subscriptions_mut(&mut self) -> &mut HashMap<String, Subscription> {
         match self {
-            Channel::Public { subscriptions } => subscriptions,
-            Channel::Private { subscriptions } => subscriptions,
+            Channel::Public { subscriptions, .. } => subscriptions,
+            Channel::Private { subscriptions, .. } => subscriptions,
+            Channel::Encrypted { subscriptions, .. } => subscriptions,
             Channel::Presence { subscriptions, .. } => subscriptions,
         }
     }
@@ -82,6 +176,11 @@ impl Channel {
 
 impl From<String> for Channel {
     fn from(name: String) -> Channel {
+        if name.starts_with("private-encrypted-") {
+            return Channel::Encrypted {
+                subscriptions: HashMap::default(),
+            };
+        }
         match name.as_str().splitn(2, "-").collect::<Vec<&str>>().as_slice() {
             ["private", ..] => Channel::Private {
                 subscriptions: HashMap::default(),
@@ -97,26 +196,467 @@ impl From<String> for Channel {
     }
 }
 
+/// A channel of this node gaining its first subscriber or losing its last one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ChannelTransition {
+    Occupied(String),
+    Vacated(String),
+}
+
+impl ChannelTransition {
+    pub(crate) fn channel(&self) -> &str {
+        match self {
+            ChannelTransition::Occupied(channel) | ChannelTransition::Vacated(channel) => channel,
+        }
+    }
+}
+
+/// The channels of an app, spread over shards locked separately so that sockets working on
+/// different channels do not contend. The locks are synchronous and never held across an
+/// `.await`: callers look at or change a channel in a closure and act on the result after.
+/// A closure that panics does not poison its shard, later callers keep using the channels.
+/// Channels are forgotten once vacated, so only channels in use take memory. The last events of
+/// cache channels are kept apart until they expire, so that publishing to a cache channel
+/// nobody subscribes to creates no channel.
+#[derive(Clone, Debug)]
+pub(crate) struct ChannelRegistry {
+    shards: Arc<[RwLock<HashMap<String, Channel>>]>,
+    cached: Arc<[Mutex<HashMap<String, CachedEvent>>]>,
+    hasher: DefaultHashBuilder,
+    /// Every subscriber gets every transition, however far behind it is.
+    transitions: Arc<Mutex<Vec<mpsc::UnboundedSender<ChannelTransition>>>>,
+}
+
+impl Default for ChannelRegistry {
+    fn default() -> Self {
+        ChannelRegistry {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            cached: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: DefaultHashBuilder::default(),
+            transitions: Arc::default(),
+        }
+    }
+}
+
+impl ChannelRegistry {
+    fn index(&self, name: &str) -> usize {
+        self.hasher.hash_one(name) as usize % SHARDS
+    }
+
+    fn shard(&self, name: &str) -> &RwLock<HashMap<String, Channel>> {
+        &self.shards[self.index(name)]
+    }
+
+    pub(crate) fn inspect<R>(&self, name: &str, f: impl FnOnce(Option<&Channel>) -> R) -> R {
+        f(self.shard(name).read().unwrap_or_else(PoisonError::into_inner).get(name))
+    }
+
+    /// Changes the channel, creating it first if needed.
+    pub(crate) fn entry<R>(&self, name: &str, f: impl FnOnce(&mut Channel) -> R) -> R {
+        let mut shard = self.shard(name).write().unwrap_or_else(PoisonError::into_inner);
+        let channel = shard.entry(name.to_owned()).or_insert_with(|| Channel::from(name.to_owned()));
+        let was_empty = channel.is_empty();
+        let result = f(channel);
+        self.settle(&mut shard, name, was_empty);
+        result
+    }
+
+    /// Changes the channel if it exists.
+    pub(crate) fn update<R>(&self, name: &str, f: impl FnOnce(&mut Channel) -> R) -> Option<R> {
+        let mut shard = self.shard(name).write().unwrap_or_else(PoisonError::into_inner);
+        let channel = shard.get_mut(name)?;
+        let was_empty = channel.is_empty();
+        let result = f(channel);
+        self.settle(&mut shard, name, was_empty);
+        Some(result)
+    }
+
+    /// Announces the transition a change made, if any, and forgets the channel once vacated.
+    /// Both happen under the shard lock so that transitions of a channel are seen in order.
+    fn settle(&self, shard: &mut HashMap<String, Channel>, name: &str, was_empty: bool) {
+        let channel = &shard[name];
+        let transition = match (was_empty, channel.is_empty()) {
+            (true, false) => Some(ChannelTransition::Occupied(name.to_owned())),
+            (false, true) => Some(ChannelTransition::Vacated(name.to_owned())),
+            _ => None,
+        };
+        if channel.is_empty() {
+            shard.remove(name);
+        }
+        if let Some(transition) = transition {
+            self.transitions.lock().unwrap_or_else(PoisonError::into_inner).retain(|subscriber| subscriber.send(transition.clone()).is_ok());
+        }
+    }
+
+    /// The occupied and vacated transitions of the channels from now on.
+    pub(crate) fn transitions(&self) -> mpsc::UnboundedReceiver<ChannelTransition> {
+        let (subscriber, transitions) = mpsc::unbounded_channel();
+        self.transitions.lock().unwrap_or_else(PoisonError::into_inner).push(subscriber);
+        transitions
+    }
+
+    /// Keeps the event for the later subscribers of its channel when that is a cache channel,
+    /// whether anybody is subscribed now or not.
+    pub(crate) fn remember(&self, event: &CustomEvent) {
+        if is_cache_channel(&event.channel) {
+            let cached = CachedEvent { event: event.clone(), expires: Instant::now() + CACHE_TTL };
+            self.cached[self.index(&event.channel)].lock().unwrap_or_else(PoisonError::into_inner).insert(event.channel.to_owned(), cached);
+        }
+    }
+
+    /// The event a cache channel replays to new subscribers, `None` for every other channel.
+    pub(crate) fn cached_event(&self, name: &str) -> Option<Option<CustomEvent>> {
+        is_cache_channel(name).then(|| self.cached(name, |cached| cached.event.clone()))
+    }
+
+    /// How much longer the cached event of the channel will be replayed.
+    pub(crate) fn cache_ttl(&self, name: &str) -> Option<Duration> {
+        self.cached(name, |cached| cached.expires.saturating_duration_since(Instant::now()))
+    }
+
+    fn cached<R>(&self, name: &str, f: impl FnOnce(&CachedEvent) -> R) -> Option<R> {
+        let cached = self.cached[self.index(name)].lock().unwrap_or_else(PoisonError::into_inner);
+        cached.get(name).filter(|cached| cached.expires > Instant::now()).map(f)
+    }
+
+    /// Sweeps out expired events every [`CACHE_SWEEP_INTERVAL`] for as long as the registry lives,
+    /// so that the events of cache channels nobody touches again are forgotten too.
+    pub(crate) fn sweep_expired_events(&self) {
+        let cached = Arc::downgrade(&self.cached);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CACHE_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(cached) = cached.upgrade() else { break };
+                let now = Instant::now();
+                for cached in cached.iter() {
+                    cached.lock().unwrap_or_else(PoisonError::into_inner).retain(|_, cached| cached.expires > now);
+                }
+            }
+        });
+    }
+
+    /// Visits every channel, one shard at a time.
+    pub(crate) fn for_each(&self, mut f: impl FnMut(&str, &Channel)) {
+        for shard in self.shards.iter() {
+            shard.read().unwrap_or_else(PoisonError::into_inner).iter().for_each(|(name, channel)| f(name, channel));
+        }
+    }
+}
+
+impl Serialize for ChannelRegistry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut channels = Vec::new();
+        self.for_each(|name, channel| channels.push((name.to_owned(), channel.clone())));
+        let mut map = serializer.serialize_map(Some(channels.len()))?;
+        for (name, channel) in &channels {
+            map.serialize_entry(name, channel)?;
+        }
+        map.end()
+    }
+}
+
+/// Whether the channel replays its last event to new subscribers: `cache-`, `private-cache-`,
+/// `private-encrypted-cache-` and `presence-cache-` channels.
+pub(crate) fn is_cache_channel(name: &str) -> bool {
+    ["cache-", "private-cache-", "private-encrypted-cache-", "presence-cache-"].iter().any(|prefix| name.starts_with(prefix))
+}
+
+/// The last event published on a cache channel and when it stops being replayed.
+#[derive(Clone, Debug)]
+struct CachedEvent {
+    event: CustomEvent,
+    expires: Instant,
+}
+
+#[repr(C)]
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct PresenceMember {
+    pub info: serde_json::Value,
+    pub connections: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct PresenceData {
+    presence: PresenceInformation,
+}
+
+impl From<HashMap<String, serde_json::Value>> for PresenceData {
+    fn from(hash: HashMap<String, serde_json::Value>) -> Self {
+        PresenceData {
+            presence: PresenceInformation {
+                ids: hash.keys().cloned().collect(),
+                count: hash.len() as u32,
+                hash,
+            },
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Debug, Serialize)]
 pub(crate) struct PresenceInformation {
     ids: Vec<String>,
-    hash: HashMap<String, HashMap<String, String>>,
+    hash: HashMap<String, serde_json::Value>,
     count: u32,
 }
 
 #[repr(C)]
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct PresenceUser {
     #[serde(rename = "user_id")]
-    id: String,
+    pub id: String,
     #[serde(rename = "user_info")]
-    info: serde_json::Value,
+    pub info: serde_json::Value,
+}
+
+impl PresenceUser {
+    /// Parses the `channel_data` of a presence subscription, which clients send as a JSON encoded string.
+    pub(crate) fn from_channel_data(channel_data: Option<&serde_json::Value>) -> Result<PresenceUser, CustomError> {
+        let channel_data = match channel_data {
+            Some(serde_json::Value::String(data)) => serde_json::from_str(data).map_err(|_| CustomError::InvalidChannelData)?,
+            Some(data) => data.clone(),
+            None => return Err(CustomError::InvalidChannelData),
+        };
+
+        let id = match channel_data.get("user_id") {
+            Some(serde_json::Value::String(id)) if !id.is_empty() => id.to_owned(),
+            Some(serde_json::Value::Number(id)) => id.to_string(),
+            _ => return Err(CustomError::InvalidChannelData),
+        };
+
+        Ok(PresenceUser {
+            id,
+            info: channel_data.get("user_info").cloned().unwrap_or_default(),
+        })
+    }
 }
 
 #[repr(C)]
 #[derive(Clone, Debug, Serialize)]
 pub(crate) struct RemovedMember {
     #[serde(rename = "user_id")]
-    id: String,
+    pub id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::app::{outbox, Channel, ChannelRegistry, ChannelTransition, CustomEvent, Frame, OutboxReceiver, Pusher, CACHE_SWEEP_INTERVAL, CACHE_TTL, ServerEvent, SlowConsumerPolicy, Subscription, json};
+
+    fn event() -> ServerEvent {
+        ServerEvent::ChannelEvent(CustomEvent {
+            event: "update".to_owned(),
+            channel: "room".to_owned(),
+            data: json!({ "payload": "x".repeat(1024) }),
+            user_id: None,
+        })
+    }
+
+    fn cached_event() -> CustomEvent {
+        CustomEvent {
+            event: "score".to_owned(),
+            channel: "cache-room".to_owned(),
+            data: json!("1-0"),
+            user_id: None,
+        }
+    }
+
+    fn subscribed(count: usize) -> (Channel, Vec<OutboxReceiver>) {
+        let mut channel = Channel::from("room".to_owned());
+        let receivers = (0..count).map(|index| {
+            let (sender, receiver) = outbox(1, SlowConsumerPolicy::default());
+            channel.add_subscription(&format!("{}.{}", index, index), Subscription { sender, data: None, user_id: None });
+            receiver
+        }).collect();
+        (channel, receivers)
+    }
+
+    #[tokio::test]
+    async fn subscribers_share_one_encoded_frame() {
+        let (channel, receivers) = subscribed(3);
+
+        assert_eq!(channel.delivery(&event(), None).send(), 0);
+
+        let frames = receivers.iter().map(|receiver| receiver.try_recv().unwrap()).collect::<Vec<Frame>>();
+        assert!(frames.windows(2).all(|pair| std::ptr::eq(pair[0].as_ptr(), pair[1].as_ptr())));
+        let sent: serde_json::Value = serde_json::from_str(&frames[0]).unwrap();
+        assert_eq!(sent["event"], "update");
+
+        // The last socket to write the frame takes its buffer instead of copying it.
+        let encoded = frames[0].as_ptr();
+        let messages = frames.into_iter().map(warp::ws::Message::from).collect::<Vec<_>>();
+        let pointers = messages.iter().map(|message| message.to_str().unwrap().as_ptr()).collect::<Vec<_>>();
+        assert_eq!(pointers.iter().filter(|&&pointer| pointer == encoded).count(), 1);
+        assert_eq!(pointers[2], encoded);
+    }
+
+    #[tokio::test]
+    async fn stalled_subscribers_do_not_hold_up_the_others() {
+        let (channel, receivers) = subscribed(2);
+        channel.subscriptions()["0.0"].sender.push(Frame::from(ServerEvent::Pong));
+
+        assert_eq!(channel.delivery(&event(), None).send(), 1);
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&receivers[1].try_recv().unwrap()).unwrap()["event"], "update");
+        assert_eq!(receivers[0].try_recv().unwrap(), Frame::from(ServerEvent::Pong));
+    }
+
+    #[tokio::test]
+    async fn vacated_channels_are_forgotten_but_keep_their_cached_event() {
+        let registry = ChannelRegistry::default();
+        let mut transitions = registry.transitions();
+        for name in ["room", "cache-room"] {
+            let (sender, _receiver) = outbox(1, SlowConsumerPolicy::default());
+            registry.entry(name, |channel| channel.add_subscription("1.1", Subscription { sender, data: None, user_id: None }));
+        }
+        registry.remember(&cached_event());
+
+        registry.update("room", |channel| channel.remove_subscription("1.1"));
+        registry.update("cache-room", |channel| channel.remove_subscription("1.1"));
+
+        assert!(registry.inspect("room", |channel| channel.is_none()));
+        assert!(registry.inspect("cache-room", |channel| channel.is_none()));
+        assert_eq!(registry.cached_event("cache-room").flatten().unwrap().event, "score");
+        for expected in [
+            ChannelTransition::Occupied("room".to_owned()),
+            ChannelTransition::Occupied("cache-room".to_owned()),
+            ChannelTransition::Vacated("room".to_owned()),
+            ChannelTransition::Vacated("cache-room".to_owned()),
+        ] {
+            assert_eq!(transitions.try_recv().unwrap(), expected);
+        }
+        assert!(transitions.try_recv().is_err());
+    }
+
+    #[test]
+    fn shards_outlive_a_panicking_closure() {
+        let registry = ChannelRegistry::default();
+        let panicked = std::panic::catch_unwind(|| registry.entry("room", |_| panic!("boom")));
+        assert!(panicked.is_err());
+
+        let (sender, _receiver) = outbox(1, SlowConsumerPolicy::default());
+        registry.entry("room", |channel| channel.add_subscription("1.1", Subscription { sender, data: None, user_id: None }));
+        assert_eq!(registry.inspect("room", |channel| channel.map(Channel::subscriptions_count)), Some(1));
+    }
+
+    #[tokio::test]
+    async fn transitions_are_kept_for_subscribers_that_fall_behind() {
+        let registry = ChannelRegistry::default();
+        let mut transitions = registry.transitions();
+        for _ in 0..5000 {
+            let (sender, _receiver) = outbox(1, SlowConsumerPolicy::default());
+            registry.entry("room", |channel| channel.add_subscription("1.1", Subscription { sender, data: None, user_id: None }));
+            registry.update("room", |channel| channel.remove_subscription("1.1"));
+        }
+
+        for _ in 0..5000 {
+            assert_eq!(transitions.try_recv().unwrap(), ChannelTransition::Occupied("room".to_owned()));
+            assert_eq!(transitions.try_recv().unwrap(), ChannelTransition::Vacated("room".to_owned()));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cached_events_expire_without_creating_channels() {
+        let pusher = Pusher::new(1, "key", "secret");
+        pusher.channels.sweep_expired_events();
+
+        pusher.publish_local(cached_event(), None).await;
+        let mut channels = 0;
+        pusher.channels.for_each(|_, _| channels += 1);
+        assert_eq!(channels, 0);
+        assert!(pusher.channels.cache_ttl("cache-room").is_some());
+
+        tokio::time::sleep(CACHE_TTL + CACHE_SWEEP_INTERVAL).await;
+        assert!(pusher.channels.cached.iter().all(|cached| cached.lock().unwrap().is_empty()));
+        assert!(pusher.channels.cached_event("cache-room").is_some_and(|event| event.is_none()));
+    }
+
+    /// Subscribes a socket, publishes to the channel and unsubscribes it again, as a short lived client would.
+    async fn churn(registry: &ChannelRegistry, name: &str, socket_id: &str) {
+        // Room for the events of the other sockets sharing the channel, which this one does not read.
+        let (sender, receiver) = outbox(1024, SlowConsumerPolicy::default());
+        registry.entry(name, |channel| channel.add_subscription(socket_id, Subscription { sender, data: None, user_id: None }));
+        let delivery = registry.inspect(name, |channel| channel.unwrap().delivery(&event(), None));
+        delivery.send();
+        receiver.recv().await.unwrap();
+        registry.update(name, |channel| channel.remove_subscription(socket_id));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn sockets_churn_concurrently_across_channels() {
+        let registry = ChannelRegistry::default();
+        let tasks = (0..16).map(|task| {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                for round in 0..200 {
+                    churn(&registry, &format!("room-{}", round % 8), &format!("{}.{}", task, round)).await;
+                }
+            })
+        });
+        for task in futures::future::join_all(tasks).await {
+            task.unwrap();
+        }
+
+        let mut channels = 0;
+        registry.for_each(|_, _| channels += 1);
+        assert_eq!(channels, 0);
+    }
+
+    /// Publishers and churning subscribers share the registry without blocking one another:
+    /// everything completes, and sockets subscribed throughout get every event exactly once.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn publishers_and_subscribers_run_concurrently() {
+        const CHANNELS: usize = 8;
+        const PUBLISHERS: usize = 8;
+        const EVENTS: usize = 96;
+        const SUBSCRIBERS: usize = 16;
+        // Every publisher's events, and the one each churning subscriber publishes per visit.
+        const EXPECTED: usize = PUBLISHERS * EVENTS + SUBSCRIBERS * EVENTS / CHANNELS;
+
+        let registry = ChannelRegistry::default();
+        let steady = (0..CHANNELS).map(|index| {
+            let (sender, receiver) = outbox(EXPECTED, SlowConsumerPolicy::default());
+            registry.entry(&format!("room-{}", index), |channel| channel.add_subscription("steady", Subscription { sender, data: None, user_id: None }));
+            receiver
+        }).collect::<Vec<_>>();
+
+        let publishers = (0..PUBLISHERS).map(|_| {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                for round in 0..EVENTS {
+                    for index in 0..CHANNELS {
+                        registry.inspect(&format!("room-{}", index), |channel| channel.unwrap().delivery(&event(), None)).send();
+                    }
+                    if round % 10 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            })
+        });
+        let subscribers = (0..SUBSCRIBERS).map(|task| {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                for round in 0..EVENTS {
+                    churn(&registry, &format!("room-{}", round % CHANNELS), &format!("{}.{}", task, round)).await;
+                }
+            })
+        });
+        let tasks = futures::future::join_all(publishers.chain(subscribers));
+        for task in tokio::time::timeout(Duration::from_secs(30), tasks).await.unwrap() {
+            task.unwrap();
+        }
+
+        for receiver in &steady {
+            let mut received = 0;
+            while receiver.try_recv().is_ok() {
+                received += 1;
+            }
+            assert_eq!(received, EXPECTED);
+            assert_eq!(receiver.dropped(), 0);
+        }
+        let mut subscriptions = 0;
+        registry.for_each(|_, channel| subscriptions += channel.subscriptions().len());
+        assert_eq!(subscriptions, CHANNELS);
+    }
 }
@@ -0,0 +1,237 @@
+use std::path::Path;
+use crate::app::{decode_master_key, Deserialize, MAX_BODY_SIZE, RedisAddress, HashMap, Pusher, PusherServer, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Result<ConfigFormat, ConfigError> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Ok(ConfigFormat::Toml),
+            Some("json") => Ok(ConfigFormat::Json),
+            Some("yaml") | Some("yml") => Ok(ConfigFormat::Yaml),
+            _ => Err(ConfigError::UnsupportedFormat(path.display().to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ConfigFormat::Toml => write!(f, "TOML"),
+            ConfigFormat::Json => write!(f, "JSON"),
+            ConfigFormat::Yaml => write!(f, "YAML"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    UnsupportedFormat(String),
+    Parse(ConfigFormat, String),
+    Invalid(Vec<String>),
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "Cannot read config file {}: {}", path, err),
+            ConfigError::UnsupportedFormat(path) => write!(f, "Config file {} must have a .toml, .json, .yaml or .yml extension", path),
+            ConfigError::Parse(format, err) => write!(f, "Invalid {} config: {}", format, err),
+            ConfigError::Invalid(problems) => write!(f, "Invalid config: {}", problems.join("; ")),
+        }
+    }
+}
+
+/// The apps served by one server, as described by a configuration file.
+#[repr(C)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub apps: Vec<Pusher>,
+    /// `redis://` url of the server relaying events between nodes; a single node without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redis_url: Option<String>,
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let format = ConfigFormat::from_path(path)?;
+        let contents = std::fs::read_to_string(path).map_err(|err| ConfigError::Io(path.display().to_string(), err))?;
+        Config::parse(&contents, format)
+    }
+
+    pub fn parse(contents: &str, format: ConfigFormat) -> Result<Config, ConfigError> {
+        let config: Config = match format {
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|err| ConfigError::Parse(format, err.to_string()))?,
+            ConfigFormat::Json => serde_json::from_str(contents).map_err(|err| ConfigError::Parse(format, err.to_string()))?,
+            ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(|err| ConfigError::Parse(format, err.to_string()))?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reports every problem at once so a broken file can be fixed in one pass.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut ids = HashMap::new();
+        let mut keys = HashMap::new();
+
+        if self.apps.is_empty() {
+            problems.push("at least one app must be configured".to_owned());
+        }
+        if self.redis_url.as_deref().is_some_and(|url| RedisAddress::parse(url).is_none()) {
+            problems.push("redis_url must look like redis://[[username]:password@]host[:port]".to_owned());
+        }
+
+        for (index, app) in self.apps.iter().enumerate() {
+            let name = format!("app #{} (id {})", index + 1, app.id);
+
+            if let Some(first) = ids.insert(app.id, index) {
+                problems.push(format!("{}: id {} is already used by app #{}", name, app.id, first + 1));
+            }
+            if let Some(first) = keys.insert(app.key.as_str(), index) {
+                problems.push(format!("{}: key {:?} is already used by app #{}", name, app.key, first + 1));
+            }
+            if app.key.trim().is_empty() {
+                problems.push(format!("{}: key must not be empty", name));
+            }
+            if app.secret.trim().is_empty() {
+                problems.push(format!("{}: secret must not be empty", name));
+            }
+            if app.capacity == Some(0) {
+                problems.push(format!("{}: capacity must be greater than 0", name));
+            }
+            if app.activity_timeout == Some(0) {
+                problems.push(format!("{}: activity_timeout must be greater than 0", name));
+            }
+            if app.max_payload_size.is_some_and(|size| size == 0 || size > MAX_BODY_SIZE) {
+                problems.push(format!("{}: max_payload_size must be between 1 and {} bytes", name, MAX_BODY_SIZE));
+            }
+            if app.encryption_master_key_base64.as_deref().is_some_and(|key| decode_master_key(key).is_none()) {
+                problems.push(format!("{}: encryption_master_key_base64 must be 32 bytes encoded as base64", name));
+            }
+            for origin in app.allowed_origins.iter().flatten().filter(|origin| origin.trim().is_empty()) {
+                problems.push(format!("{}: allowed origin {:?} must not be empty", name, origin));
+            }
+            for webhook in app.webhooks.iter().flatten() {
+                if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                    problems.push(format!("{}: webhook url {:?} must be an http or https url", name, webhook.url));
+                }
+            }
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(ConfigError::Invalid(problems)) }
+    }
+}
+
+impl TryFrom<Config> for PusherServer {
+    type Error = ConfigError;
+
+    fn try_from(config: Config) -> Result<Self, Self::Error> {
+        config.validate()?;
+        let mut apps = config.apps.into_iter();
+        let mut server = PusherServer::new(apps.next().expect("validated config has apps"));
+        for app in apps {
+            server.add(app);
+        }
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::{Config, ConfigError, ConfigFormat, PusherServer, SlowConsumerPolicy};
+
+    const TOML: &str = r#"
+        redis_url = "redis://127.0.0.1:6379"
+
+        [[apps]]
+        id = 1
+        key = "key-1"
+        secret = "secret-1"
+        name = "first"
+        capacity = 100
+        client_messages_enabled = true
+        allowed_origins = ["https://*.example.com"]
+        slow_consumer_policy = "drop_oldest"
+
+        [[apps.webhooks]]
+        url = "https://example.com/pusher"
+        event_types = ["channel_occupied"]
+
+        [[apps]]
+        id = 2
+        key = "key-2"
+        secret = "secret-2"
+    "#;
+
+    fn problems(result: Result<Config, ConfigError>) -> Vec<String> {
+        match result {
+            Err(ConfigError::Invalid(problems)) => problems,
+            other => panic!("expected validation problems, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_every_format() {
+        let toml = Config::parse(TOML, ConfigFormat::Toml).unwrap();
+        assert_eq!(toml.apps.len(), 2);
+        assert_eq!(toml.redis_url.as_deref(), Some("redis://127.0.0.1:6379"));
+        assert_eq!(toml.apps[0].capacity, Some(100));
+        assert_eq!(toml.apps[0].webhooks.as_ref().unwrap()[0].url, "https://example.com/pusher");
+        assert_eq!(toml.apps[0].slow_consumer_policy, Some(SlowConsumerPolicy::DropOldest));
+        assert_eq!(toml.apps[1].client_messages_enabled, None);
+
+        let json = r#"{"apps": [{"id": 1, "key": "key", "secret": "secret", "statistics_enabled": true}]}"#;
+        assert_eq!(Config::parse(json, ConfigFormat::Json).unwrap().apps[0].statistics_enabled, Some(true));
+
+        let yaml = "apps:\n  - id: 7\n    key: key\n    secret: secret\n    path: /pusher\n";
+        assert_eq!(Config::parse(yaml, ConfigFormat::Yaml).unwrap().apps[0].path.as_deref(), Some("/pusher"));
+    }
+
+    #[test]
+    fn reports_duplicate_ids_and_keys() {
+        let json = r#"{"apps": [
+            {"id": 1, "key": "a", "secret": "s"},
+            {"id": 1, "key": "b", "secret": "s"},
+            {"id": 2, "key": "a", "secret": "s"}
+        ]}"#;
+
+        assert_eq!(problems(Config::parse(json, ConfigFormat::Json)), vec![
+            "app #2 (id 1): id 1 is already used by app #1".to_owned(),
+            "app #3 (id 2): key \"a\" is already used by app #1".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn reports_malformed_entries() {
+        let json = r#"{"redis_url": "localhost", "apps": [{"id": 1, "key": "", "secret": "s", "capacity": 0, "webhooks": [{"url": "ftp://x"}], "encryption_master_key_base64": "c2hvcnQ=", "max_payload_size": 0}]}"#;
+        assert_eq!(problems(Config::parse(json, ConfigFormat::Json)).len(), 6);
+        assert!(problems(Config::parse(r#"{"apps": []}"#, ConfigFormat::Json))[0].contains("at least one app"));
+
+        let missing_secret = Config::parse("[[apps]]\nid = 1\nkey = \"key\"\n", ConfigFormat::Toml).unwrap_err();
+        assert!(matches!(missing_secret, ConfigError::Parse(ConfigFormat::Toml, ref err) if err.contains("secret")));
+
+        let unknown_field = Config::parse(r#"{"apps": [{"id": 1, "key": "k", "secret": "s", "capacty": 1}]}"#, ConfigFormat::Json).unwrap_err();
+        assert!(unknown_field.to_string().contains("capacty"));
+
+        assert!(matches!(ConfigFormat::from_path("apps.ini".as_ref()), Err(ConfigError::UnsupportedFormat(_))));
+    }
+
+    #[test]
+    fn builds_server_with_every_app() {
+        let server = PusherServer::try_from(Config::parse(TOML, ConfigFormat::Toml).unwrap()).unwrap();
+
+        assert_eq!(server.find("key-1").unwrap().id, 1);
+        assert_eq!(server.find_by_id(2).unwrap().key, "key-2");
+    }
+}
@@ -12,6 +12,25 @@ pub(crate) enum CustomError {
     AppIdNotFound,
     AuthKeyMismatch,
     AuthSignatureError,
+    InvalidChannelData,
+    ClientEventsDisabled,
+    ClientEventInvalidName,
+    ClientEventForbidden,
+    BatchTooLarge,
+    AuthTimestampExpired,
+    BodyMd5Mismatch,
+    InvalidBody,
+    InvalidUserData,
+    UserCountNotPresence,
+    SubscriptionCountDisabled,
+    EncryptedDataInvalid,
+    EncryptedChannelNotAlone,
+    CacheNotCacheChannel,
+    ChannelNameInvalid,
+    ChannelNameTooLong,
+    TooManyChannels,
+    EventNameTooLong,
+    PayloadTooLarge,
 }
 
 impl warp::reject::Reject for CustomError {}
@@ -34,6 +53,90 @@ impl std::fmt::Display for CustomError {
             CustomError::AppIdNotFound => write!(f, "There is no app with the app_id you specified"),
             CustomError::AuthKeyMismatch => write!(f, "Auth credentials is wrong"),
             CustomError::AuthSignatureError => write!(f, "Invalid Auth Signature."),
+            CustomError::InvalidChannelData => write!(f, "Presence channel_data must contain a user_id"),
+            CustomError::ClientEventsDisabled => write!(f, "The app does not have client messaging enabled"),
+            CustomError::ClientEventInvalidName => write!(f, "Client event names must be prefixed with client-"),
+            CustomError::ClientEventForbidden => write!(f, "Client events are only allowed on subscribed private and presence channels"),
+            CustomError::BatchTooLarge => write!(f, "Batch cannot contain more than 10 events"),
+            CustomError::AuthTimestampExpired => write!(f, "Timestamp expired: auth_timestamp is outside the allowed window"),
+            CustomError::BodyMd5Mismatch => write!(f, "body_md5 does not match the request body"),
+            CustomError::InvalidBody => write!(f, "Invalid Body"),
+            CustomError::InvalidUserData => write!(f, "Signin user_data must contain a string id"),
+            CustomError::UserCountNotPresence => write!(f, "user_count may only be requested for presence channels"),
+            CustomError::SubscriptionCountDisabled => write!(f, "subscription_count is not enabled for this app"),
+            CustomError::CacheNotCacheChannel => write!(f, "cache may only be requested for cache channels"),
+            CustomError::EncryptedDataInvalid => write!(f, "Data published to private-encrypted channels must be an encrypted envelope with nonce and ciphertext"),
+            CustomError::EncryptedChannelNotAlone => write!(f, "Cannot trigger on several channels when one of them is a private-encrypted channel"),
+            CustomError::ChannelNameInvalid => write!(f, "Channel names must be formatted as such: ^[-a-zA-Z0-9_=@,.;]+$"),
+            CustomError::ChannelNameTooLong => write!(f, "Channel names must be at most 200 characters"),
+            CustomError::TooManyChannels => write!(f, "Cannot trigger on more than 100 channels"),
+            CustomError::EventNameTooLong => write!(f, "Event names must be at most 200 characters"),
+            CustomError::PayloadTooLarge => write!(f, "Event data is larger than the maximum payload size"),
+        }
+    }
+}
+
+/// Errors sent to websocket clients as `pusher:error`. The code range tells the client what
+/// to do next: 4000-4099 closes the connection for good, 4100-4199 asks it to reconnect
+/// with a backoff, 4200-4299 to reconnect immediately and 4300-4399 keeps it open.
+#[derive(Debug)]
+pub(crate) enum ProtocolError {
+    AppNotFound,
+    OverConnectionQuota,
+    InvalidVersionFormat,
+    UnsupportedProtocolVersion(String),
+    NoProtocolVersion,
+    OriginNotAllowed(String),
+    SigninFailed(CustomError),
+    ConnectionTerminated,
+    OverCapacity,
+    PongNotReceived,
+    InvalidMessage(String),
+    NotSubscribed(String),
+    ClientEventRejected(CustomError),
+}
+
+impl ProtocolError {
+    pub(crate) fn code(&self) -> u16 {
+        match *self {
+            ProtocolError::AppNotFound => 4001,
+            ProtocolError::OverConnectionQuota => 4004,
+            ProtocolError::InvalidVersionFormat => 4006,
+            ProtocolError::UnsupportedProtocolVersion(_) => 4007,
+            ProtocolError::NoProtocolVersion => 4008,
+            ProtocolError::OriginNotAllowed(_) => 4009,
+            ProtocolError::SigninFailed(_) => 4009,
+            ProtocolError::ConnectionTerminated => 4009,
+            ProtocolError::OverCapacity => 4100,
+            ProtocolError::PongNotReceived => 4201,
+            ProtocolError::InvalidMessage(_) => 4300,
+            ProtocolError::NotSubscribed(_) => 4300,
+            ProtocolError::ClientEventRejected(_) => 4301,
+        }
+    }
+
+    /// Whether the connection is closed, with the error code as close code, after reporting it.
+    pub(crate) fn closes_connection(&self) -> bool {
+        self.code() < 4300
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProtocolError::AppNotFound => write!(f, "Application does not exist"),
+            ProtocolError::OverConnectionQuota => write!(f, "Application is over connection quota"),
+            ProtocolError::InvalidVersionFormat => write!(f, "Invalid version string format"),
+            ProtocolError::UnsupportedProtocolVersion(version) => write!(f, "Unsupported protocol version {}", version),
+            ProtocolError::NoProtocolVersion => write!(f, "No protocol version supplied"),
+            ProtocolError::OriginNotAllowed(origin) => write!(f, "Origin {} is not allowed", origin),
+            ProtocolError::SigninFailed(err) => write!(f, "Signin failed: {}", err),
+            ProtocolError::ConnectionTerminated => write!(f, "Connection terminated by the server"),
+            ProtocolError::OverCapacity => write!(f, "Over capacity"),
+            ProtocolError::PongNotReceived => write!(f, "Pong reply not received"),
+            ProtocolError::InvalidMessage(reason) => write!(f, "Invalid message: {}", reason),
+            ProtocolError::NotSubscribed(channel) => write!(f, "No current subscription to channel {}, or subscription in progress", channel),
+            ProtocolError::ClientEventRejected(err) => write!(f, "Client event rejected: {}", err),
         }
     }
 }
@@ -1,4 +1,5 @@
-use crate::app::{as_json_string, ConnectionInfo, Pusher, HashSet, PresenceInformation, PresenceUser, RemovedMember, Deserialize, Serialize, CustomError};
+use std::sync::Arc;
+use crate::app::{as_json_string, is_encrypted_envelope, validate_event_channels, validate_event_name, validate_payload_size, Channel, ClusterMessage, ConnectionInfo, Pusher, HashSet, PresenceData, PresenceUser, RemovedMember, SigninInfo, SERVER_TO_USER_PREFIX, DEFAULT_MAX_PAYLOAD_SIZE, Deserialize, Serialize, CustomError, ProtocolError};
 
 #[repr(C)]
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -8,11 +9,14 @@ pub(crate) struct EventRequestBody {
     pub channels: Option<HashSet<String>>,
     pub channel: Option<String>,
     pub socket_id: Option<String>,
+    pub info: Option<String>,
 }
 
 impl EventRequestBody {
     #[inline(always)]
     pub(crate) async fn payload_publish(&self, pusher: Pusher) -> Result<(), warp::Rejection> {
+        self.ensure_valid_event(&pusher)?;
+        self.ensure_valid_encryption()?;
         match &self.channel {
             None => {
                 match &self.channels {
@@ -34,15 +38,80 @@ impl EventRequestBody {
     }
     #[inline(always)]
     async fn publish(&self, pusher: Pusher, channel_name: String) {
-        let event = ServerEvent::ChannelEvent(CustomEvent {
+        let event = CustomEvent {
             event: self.name.to_owned(),
             data: self.data.to_owned().into(),
             channel: channel_name.to_owned(),
-            user_id: self.socket_id.to_owned(),
-        });
-        if let Some(channel) = pusher.channels.read().await.get(&channel_name) {
-            channel.publish(event).await.unwrap();
+            user_id: None,
+        };
+        if let Some(user_id) = channel_name.strip_prefix(SERVER_TO_USER_PREFIX) {
+            pusher.publish_to_user(user_id, ServerEvent::ChannelEvent(event.clone())).await;
+            pusher.cluster.broadcast(ClusterMessage::UserEvent { user_id: user_id.to_owned(), event });
+            return;
         }
+        pusher.publish_local(event.clone(), self.socket_id.as_deref()).await;
+        pusher.cluster.broadcast(ClusterMessage::ChannelEvent { event, except: self.socket_id.to_owned() });
+    }
+    /// Applies Pusher's limits on the event name, its channels and the size of its data.
+    pub(crate) fn ensure_valid_event(&self, pusher: &Pusher) -> Result<(), warp::Rejection> {
+        let channels = self.channel.iter().chain(self.channels.iter().flatten()).map(String::as_str).collect::<Vec<&str>>();
+        validate_event_name(&self.name)
+            .and_then(|_| validate_event_channels(channels.into_iter()))
+            .and_then(|_| validate_payload_size(&self.data, pusher.max_payload_size.unwrap_or(DEFAULT_MAX_PAYLOAD_SIZE)))
+            .map_err(warp::reject::custom)
+    }
+    /// The server cannot read what it relays on encrypted channels, but it refuses anything
+    /// that is not an encrypted envelope so plaintext never reaches them by mistake. Every
+    /// encrypted channel has its own key, so an envelope is only ever for one channel.
+    pub(crate) fn ensure_valid_encryption(&self) -> Result<(), warp::Rejection> {
+        let channels = self.channel.iter().chain(self.channels.iter().flatten()).collect::<Vec<&String>>();
+        if !channels.iter().any(|channel| Channel::from(channel.to_string()).is_encrypted()) {
+            return Ok(());
+        }
+        if channels.len() > 1 {
+            return Err(warp::reject::custom(CustomError::EncryptedChannelNotAlone));
+        }
+        if !is_encrypted_envelope(&self.data) {
+            return Err(warp::reject::custom(CustomError::EncryptedDataInvalid));
+        }
+        Ok(())
+    }
+    /// Attributes requested through the comma separated `info` field.
+    pub(crate) fn info_attributes(&self) -> impl Iterator<Item = &str> {
+        self.info.iter().flat_map(|info| info.split(',')).map(str::trim)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct BatchEventRequestBody {
+    pub batch: Vec<EventRequestBody>,
+}
+
+impl BatchEventRequestBody {
+    pub(crate) const MAX_EVENTS: usize = 10;
+
+    #[inline(always)]
+    pub(crate) async fn payload_publish(&self, pusher: Pusher) -> Result<(), warp::Rejection> {
+        if self.batch.len() > Self::MAX_EVENTS {
+            return Err(warp::reject::custom(CustomError::BatchTooLarge));
+        }
+        if self.batch.iter().any(|event| event.channel.is_none()) {
+            return Err(warp::reject::custom(CustomError::EventChannelEmpty));
+        }
+        for event in &self.batch {
+            event.ensure_valid_event(&pusher)?;
+            event.ensure_valid_encryption()?;
+        }
+
+        for event in &self.batch {
+            event.payload_publish(pusher.clone()).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn has_info(&self) -> bool {
+        self.batch.iter().any(|event| event.info.is_some())
     }
 }
 
@@ -59,7 +128,12 @@ pub(crate) enum ClientEvent {
     Unsubscribe {
         channel: String,
     },
+    Signin {
+        auth: Option<String>,
+        user_data: String,
+    },
     Ping,
+    Pong,
     ChannelEvent {
         event: String,
         channel: String,
@@ -81,8 +155,14 @@ enum PusherClientEventJSON {
     #[serde(rename = "pusher:unsubscribe")]
     Unsubscribe { channel: String },
 
+    #[serde(rename = "pusher:signin")]
+    Signin { auth: Option<String>, user_data: String },
+
     #[serde(rename = "pusher:ping")]
-    Ping(Option<serde_json::Value>),
+    Ping(#[allow(dead_code)] Option<serde_json::Value>),
+
+    #[serde(rename = "pusher:pong")]
+    Pong(#[allow(dead_code)] Option<serde_json::Value>),
 }
 
 impl From<PusherClientEventJSON> for PusherClientEvent {
@@ -99,7 +179,9 @@ impl From<PusherClientEventJSON> for PusherClientEvent {
                 channel_data,
             },
             Unsubscribe { channel } => PusherClientEvent::Unsubscribe { channel },
+            Signin { auth, user_data } => PusherClientEvent::Signin { auth, user_data },
             Ping(_) => PusherClientEvent::Ping,
+            Pong(_) => PusherClientEvent::Pong,
         }
     }
 }
@@ -116,7 +198,12 @@ enum PusherClientEvent {
     Unsubscribe {
         channel: String,
     },
+    Signin {
+        auth: Option<String>,
+        user_data: String,
+    },
     Ping,
+    Pong,
 }
 
 #[repr(C)]
@@ -150,7 +237,9 @@ impl From<ClientEventJSON> for ClientEvent {
                 channel_data,
             },
             PusherEvent(Unsubscribe { channel }) => ClientEvent::Unsubscribe { channel },
+            PusherEvent(Signin { auth, user_data }) => ClientEvent::Signin { auth, user_data },
             PusherEvent(Ping) => ClientEvent::Ping,
+            PusherEvent(Pong) => ClientEvent::Pong,
             CustomEvent(CustomClientEvent { event, channel, data }) => {
                 ClientEvent::ChannelEvent { event, channel, data }
             }
@@ -159,16 +248,39 @@ impl From<ClientEventJSON> for ClientEvent {
 }
 
 #[repr(C)]
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct CustomEvent {
     pub event: String,
     pub channel: String,
     #[serde(with = "as_json_string")]
     pub data: serde_json::Value,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub user_id: Option<String>,
 }
 
+#[repr(C)]
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SubscriptionErrorInfo {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub error: String,
+    pub status: u16,
+}
+
+impl From<CustomError> for SubscriptionErrorInfo {
+    fn from(err: CustomError) -> Self {
+        let (kind, status) = match err {
+            CustomError::AuthKeyMismatch | CustomError::AuthSignatureError => ("AuthError", 401),
+            _ => ("SubscriptionError", 400),
+        };
+        SubscriptionErrorInfo {
+            kind: kind.to_owned(),
+            error: err.to_string(),
+            status,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Debug, Serialize)]
 #[serde(tag = "event")]
@@ -186,14 +298,35 @@ pub(crate) enum ServerEvent {
         code: Option<u16>,
     },
 
+    #[serde(rename = "pusher:ping")]
+    Ping,
+
     #[serde(rename = "pusher:pong")]
     Pong,
 
+    #[serde(rename = "pusher:signin_success")]
+    SigninSuccess {
+        #[serde(with = "as_json_string")]
+        data: SigninInfo,
+    },
+
+    #[serde(rename = "pusher:cache_miss")]
+    CacheMiss {
+        channel: String,
+    },
+
     #[serde(rename = "pusher_internal:subscription_succeeded")]
     SubscriptionSucceeded {
         channel: String,
         #[serde(with = "as_json_string")]
-        data: Option<PresenceInformation>,
+        data: Option<PresenceData>,
+    },
+
+    #[serde(rename = "pusher:subscription_error")]
+    SubscriptionError {
+        channel: String,
+        #[serde(with = "as_json_string")]
+        data: SubscriptionErrorInfo,
     },
 
     #[serde(rename = "pusher_internal:member_added")]
@@ -213,8 +346,56 @@ pub(crate) enum ServerEvent {
     ChannelEvent(CustomEvent),
 }
 
+/// A server event serialized once and shared by every socket it is sent to, so
+/// publishing to a large channel costs one encoding instead of one per subscriber.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Frame(Arc<String>);
+
+impl From<&ServerEvent> for Frame {
+    fn from(event: &ServerEvent) -> Frame {
+        Frame(Arc::new(serde_json::to_string(event).unwrap()))
+    }
+}
+
+impl From<ServerEvent> for Frame {
+    fn from(event: ServerEvent) -> Frame {
+        Frame::from(&event)
+    }
+}
+
+impl From<ProtocolError> for Frame {
+    fn from(err: ProtocolError) -> Frame {
+        Frame::from(ServerEvent::from(err))
+    }
+}
+
+impl std::ops::Deref for Frame {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// warp only takes an owned `String`, so each socket writing a shared frame copies the encoded
+/// text, except the last one which takes the buffer itself.
+impl From<Frame> for warp::ws::Message {
+    fn from(frame: Frame) -> warp::ws::Message {
+        warp::ws::Message::text(Arc::unwrap_or_clone(frame.0))
+    }
+}
+
 impl From<ServerEvent> for warp::ws::Message {
     fn from(event: ServerEvent) -> warp::ws::Message {
         warp::ws::Message::text(serde_json::to_string(&event).unwrap())
     }
 }
+
+impl From<ProtocolError> for ServerEvent {
+    fn from(err: ProtocolError) -> Self {
+        ServerEvent::Error {
+            message: err.to_string(),
+            code: Some(err.code()),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::app::Pusher;
+
+/// Content type of the Prometheus text exposition format.
+pub(crate) const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Counters of one app, kept by the websocket and REST handlers and rendered by [`render_metrics`].
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    dropped_messages: AtomicU64,
+    auth_failures: Mutex<BTreeMap<&'static str, u64>>,
+    api_requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+}
+
+impl Metrics {
+    pub(crate) fn message_received(&self, bytes: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_sent(&self, bytes: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Messages that could not be handed to a socket, e.g. because it was closing.
+    pub(crate) fn messages_dropped(&self, count: usize) {
+        self.dropped_messages.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// A rejected signature, `source` being `api` or `websocket`.
+    pub(crate) fn auth_failed(&self, source: &'static str) {
+        *self.auth_failures.lock().unwrap().entry(source).or_default() += 1;
+    }
+
+    pub(crate) fn api_request(&self, route: &'static str, status: u16) {
+        *self.api_requests.lock().unwrap().entry((route, status)).or_default() += 1;
+    }
+}
+
+/// The app id and route name of a REST API path, `None` outside `/apps/{id}/`.
+/// Routes are named rather than labelled with the path to keep channel names out of the labels.
+pub(crate) fn api_route(path: &str) -> Option<(u32, &'static str)> {
+    let segments = path.trim_matches('/').split('/').collect::<Vec<&str>>();
+    let (app_id, route) = match segments.as_slice() {
+        ["apps", app_id, "events"] => (app_id, "events"),
+        ["apps", app_id, "batch_events"] => (app_id, "batch_events"),
+        ["apps", app_id, "channels"] => (app_id, "channels"),
+        ["apps", app_id, "channels", _] => (app_id, "channel"),
+        ["apps", app_id, "channels", _, "users"] => (app_id, "channel_users"),
+        ["apps", app_id, "stats"] => (app_id, "stats"),
+        ["apps", app_id, "users", _, "terminate_connections"] => (app_id, "terminate_user_connections"),
+        ["apps", app_id, ..] => (app_id, "unknown"),
+        _ => return None,
+    };
+    Some((app_id.parse().ok()?, route))
+}
+
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<(String, u64)>,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Self {
+        Family { name, kind, help, samples: Vec::new() }
+    }
+}
+
+/// Renders the metrics of every app in the Prometheus text format. Gauges describe this node
+/// only; apps with `statistics_enabled = false` are left out.
+pub(crate) async fn render_metrics<'a>(apps: impl Iterator<Item = &'a Pusher>) -> String {
+    let mut connections = Family::new("pusher_connections", "gauge", "Open websocket connections.");
+    let mut channels = Family::new("pusher_channels", "gauge", "Occupied channels.");
+    let mut subscriptions = Family::new("pusher_subscriptions", "gauge", "Channel subscriptions.");
+    let mut messages_received = Family::new("pusher_websocket_messages_received_total", "counter", "Messages received from websocket clients.");
+    let mut messages_sent = Family::new("pusher_websocket_messages_sent_total", "counter", "Messages sent to websocket clients.");
+    let mut bytes_received = Family::new("pusher_websocket_bytes_received_total", "counter", "Bytes received from websocket clients.");
+    let mut bytes_sent = Family::new("pusher_websocket_bytes_sent_total", "counter", "Bytes sent to websocket clients.");
+    let mut dropped = Family::new("pusher_dropped_messages_total", "counter", "Messages that could not be delivered to a socket.");
+    let mut auth_failures = Family::new("pusher_auth_failures_total", "counter", "Rejected signatures by source.");
+    let mut api_requests = Family::new("pusher_api_requests_total", "counter", "REST API requests by route and status.");
+
+    let mut apps = apps.filter(|app| app.statistics_enabled != Some(false)).collect::<Vec<&Pusher>>();
+    apps.sort_by_key(|app| app.id);
+    for app in apps {
+        let labels = format!("app_id=\"{}\"", app.id);
+        let metrics = &app.metrics;
+        let (mut occupied, mut subscribed) = (0, 0);
+        app.channels.for_each(|_, channel| if !channel.is_empty() {
+            occupied += 1;
+            subscribed += channel.subscriptions_count() as u64;
+        });
+        channels.samples.push((labels.clone(), occupied));
+        subscriptions.samples.push((labels.clone(), subscribed));
+        connections.samples.push((labels.clone(), app.connections_count() as u64));
+        messages_received.samples.push((labels.clone(), metrics.messages_received.load(Ordering::Relaxed)));
+        messages_sent.samples.push((labels.clone(), metrics.messages_sent.load(Ordering::Relaxed)));
+        bytes_received.samples.push((labels.clone(), metrics.bytes_received.load(Ordering::Relaxed)));
+        bytes_sent.samples.push((labels.clone(), metrics.bytes_sent.load(Ordering::Relaxed)));
+        dropped.samples.push((labels.clone(), metrics.dropped_messages.load(Ordering::Relaxed)));
+        for (source, count) in metrics.auth_failures.lock().unwrap().iter() {
+            auth_failures.samples.push((format!("{},source=\"{}\"", labels, source), *count));
+        }
+        for ((route, status), count) in metrics.api_requests.lock().unwrap().iter() {
+            api_requests.samples.push((format!("{},route=\"{}\",status=\"{}\"", labels, route, status), *count));
+        }
+    }
+
+    let mut output = String::new();
+    for family in [connections, channels, subscriptions, messages_received, messages_sent, bytes_received, bytes_sent, dropped, auth_failures, api_requests] {
+        let _ = writeln!(output, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(output, "# TYPE {} {}", family.name, family.kind);
+        for (labels, value) in family.samples {
+            let _ = writeln!(output, "{}{{{}}} {}", family.name, labels, value);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::{api_route, render_metrics, Pusher};
+    use crate::app::testing::subscribe;
+
+    #[test]
+    fn names_api_routes() {
+        assert_eq!(api_route("/apps/1/events"), Some((1, "events")));
+        assert_eq!(api_route("/apps/1/channels/presence-room/users"), Some((1, "channel_users")));
+        assert_eq!(api_route("/apps/2/users/alice/terminate_connections"), Some((2, "terminate_user_connections")));
+        assert_eq!(api_route("/apps/2/nothing/here"), Some((2, "unknown")));
+        assert_eq!(api_route("/apps/key/events"), None);
+        assert_eq!(api_route("/app/key"), None);
+    }
+
+    #[tokio::test]
+    async fn renders_gauges_and_counters_per_app() {
+        let first = Pusher::new(1, "key", "secret");
+        let mut hidden = Pusher::new(2, "other", "secret");
+        hidden.set_statistics_enabled(false);
+        let _receiver = subscribe(&first, "room", "1.1", None).await;
+        first.metrics.message_received(10);
+        first.metrics.message_sent(25);
+        first.metrics.messages_dropped(2);
+        first.metrics.auth_failed("api");
+        first.metrics.api_request("events", 200);
+        first.metrics.api_request("events", 200);
+        hidden.metrics.api_request("events", 200);
+
+        let output = render_metrics([&first, &hidden].into_iter()).await;
+
+        for line in [
+            "# TYPE pusher_connections gauge",
+            "pusher_connections{app_id=\"1\"} 0",
+            "pusher_channels{app_id=\"1\"} 1",
+            "pusher_subscriptions{app_id=\"1\"} 1",
+            "pusher_websocket_messages_received_total{app_id=\"1\"} 1",
+            "pusher_websocket_bytes_sent_total{app_id=\"1\"} 25",
+            "pusher_dropped_messages_total{app_id=\"1\"} 2",
+            "pusher_auth_failures_total{app_id=\"1\",source=\"api\"} 1",
+            "pusher_api_requests_total{app_id=\"1\",route=\"events\",status=\"200\"} 2",
+        ] {
+            assert!(output.lines().any(|rendered| rendered == line), "missing {:?} in\n{}", line, output);
+        }
+        assert!(!output.contains("app_id=\"2\""));
+    }
+}
@@ -4,13 +4,34 @@ mod channels;
 mod utils;
 mod serdes;
 mod errors;
+mod webhooks;
+mod config;
+mod users;
+mod adapter;
+mod redis;
+mod metrics;
+mod outbox;
+#[cfg(test)]
+pub(crate) mod testing;
 
 pub(crate) use serdes::*;
 pub(crate) use pusher::*;
 pub(crate) use channels::*;
 pub(crate) use events::*;
 pub(crate) use utils::*;
-pub(crate) use errors::CustomError;
+pub(crate) use webhooks::*;
+pub(crate) use users::*;
+pub(crate) use adapter::*;
+pub(crate) use redis::*;
+pub(crate) use metrics::*;
+pub(crate) use outbox::*;
+pub use config::{Config, ConfigError, ConfigFormat};
+pub use pusher::Pusher;
+pub use webhooks::Webhook;
+pub use adapter::{Adapter, MemoryAdapter};
+pub use redis::RedisAdapter;
+pub use outbox::SlowConsumerPolicy;
+pub(crate) use errors::{CustomError, ProtocolError};
 pub(crate) use hashbrown::{HashSet, HashMap};
 pub(crate) use serde::{Deserialize, Serialize};
 pub(crate) use serde_json::json;
@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::Notify;
+use tokio::sync::mpsc::error::SendError;
+use crate::app::{Deserialize, Frame, Serialize};
+
+/// Number of frames queued for a socket before its app's [`SlowConsumerPolicy`] applies.
+pub(crate) const OUTBOX_CAPACITY: usize = 1024;
+
+/// What happens to a message for a socket whose queue is full, so that a client that
+/// stops reading never holds up the publisher.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Discards the oldest queued message to make room.
+    DropOldest,
+    /// Discards the message.
+    #[default]
+    DropNewest,
+    /// Closes the connection with error 4100.
+    Disconnect,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    frames: VecDeque<Frame>,
+    /// Set once the receiver or the last outbox is gone.
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct State {
+    queue: Mutex<Queue>,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    senders: AtomicUsize,
+    dropped: AtomicU64,
+    /// Wakes the writer when a frame is queued or the outbox closes.
+    readable: Notify,
+    /// Wakes a sender waiting for room when a frame is taken or the outbox closes.
+    writable: Notify,
+    overflowed: Notify,
+}
+
+impl State {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap()
+    }
+
+    fn close(&self) {
+        self.queue().closed = true;
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+}
+
+/// The queue of frames waiting to be written to one socket, shared by every subscription of it.
+#[derive(Debug)]
+pub(crate) struct Outbox {
+    state: Arc<State>,
+}
+
+/// The socket writer's end of an [`Outbox`]. Dropping it closes the outbox.
+#[derive(Debug)]
+pub(crate) struct OutboxReceiver {
+    state: Arc<State>,
+}
+
+pub(crate) fn outbox(capacity: usize, policy: SlowConsumerPolicy) -> (Outbox, OutboxReceiver) {
+    let state = Arc::new(State {
+        queue: Mutex::default(),
+        capacity,
+        policy,
+        senders: AtomicUsize::new(1),
+        dropped: AtomicU64::new(0),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        overflowed: Notify::new(),
+    });
+    (Outbox { state: state.clone() }, OutboxReceiver { state })
+}
+
+impl Outbox {
+    /// Queues a frame the socket asked for, waiting for room if need be.
+    pub(crate) async fn send(&self, frame: Frame) -> Result<(), SendError<Frame>> {
+        loop {
+            // Registered before looking at the queue, so that room made in between is not missed.
+            let writable = self.state.writable.notified();
+            {
+                let mut queue = self.state.queue();
+                if queue.closed {
+                    return Err(SendError(frame));
+                }
+                if queue.frames.len() < self.state.capacity {
+                    queue.frames.push_back(frame);
+                    drop(queue);
+                    self.state.readable.notify_one();
+                    return Ok(());
+                }
+            }
+            writable.await;
+        }
+    }
+
+    /// Queues a published frame without waiting, applying the slow consumer policy when the
+    /// queue is full. Returns false when a frame was lost, or the socket is gone.
+    pub(crate) fn push(&self, frame: Frame) -> bool {
+        let mut queue = self.state.queue();
+        if queue.closed {
+            return false;
+        }
+        if queue.frames.len() < self.state.capacity {
+            queue.frames.push_back(frame);
+            drop(queue);
+            self.state.readable.notify_one();
+            return true;
+        }
+        self.state.dropped.fetch_add(1, Ordering::Relaxed);
+        match self.state.policy {
+            SlowConsumerPolicy::DropOldest => {
+                queue.frames.pop_front();
+                queue.frames.push_back(frame);
+            }
+            SlowConsumerPolicy::DropNewest => {}
+            SlowConsumerPolicy::Disconnect => self.state.overflowed.notify_one(),
+        }
+        false
+    }
+}
+
+impl Clone for Outbox {
+    fn clone(&self) -> Self {
+        self.state.senders.fetch_add(1, Ordering::Relaxed);
+        Outbox { state: self.state.clone() }
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        if self.state.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.state.close();
+        }
+    }
+}
+
+impl OutboxReceiver {
+    /// The next frame to write, or `None` once every [`Outbox`] is gone.
+    pub(crate) async fn recv(&self) -> Option<Frame> {
+        loop {
+            let readable = self.state.readable.notified();
+            {
+                let mut queue = self.state.queue();
+                if let Some(frame) = queue.frames.pop_front() {
+                    drop(queue);
+                    self.state.writable.notify_one();
+                    return Some(frame);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            readable.await;
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn try_recv(&self) -> Result<Frame, tokio::sync::mpsc::error::TryRecvError> {
+        use tokio::sync::mpsc::error::TryRecvError;
+        let mut queue = self.state.queue();
+        match queue.frames.pop_front() {
+            Some(frame) => Ok(frame),
+            None if queue.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Completes once a frame was dropped under [`SlowConsumerPolicy::Disconnect`].
+    pub(crate) async fn overflowed(&self) {
+        self.state.overflowed.notified().await
+    }
+
+    /// Frames this socket lost because it did not keep up.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.state.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for OutboxReceiver {
+    fn drop(&mut self) {
+        self.state.queue().frames.clear();
+        self.state.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::app::{outbox, Frame, ServerEvent, SlowConsumerPolicy};
+
+    fn frames(count: usize) -> Vec<Frame> {
+        (0..count).map(|index| Frame::from(ServerEvent::CacheMiss { channel: index.to_string() })).collect()
+    }
+
+    #[tokio::test]
+    async fn full_queues_drop_newest_by_default() {
+        let (outbox, receiver) = outbox(2, SlowConsumerPolicy::default());
+        let frames = frames(3);
+        assert!(outbox.push(frames[0].clone()));
+        assert!(outbox.push(frames[1].clone()));
+        assert!(!outbox.push(frames[2].clone()));
+
+        assert_eq!(receiver.dropped(), 1);
+        assert_eq!(receiver.recv().await.unwrap(), frames[0]);
+        assert_eq!(receiver.recv().await.unwrap(), frames[1]);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn full_queues_can_drop_oldest() {
+        let (outbox, receiver) = outbox(2, SlowConsumerPolicy::DropOldest);
+        let frames = frames(3);
+        for frame in &frames {
+            outbox.push(frame.clone());
+        }
+
+        assert_eq!(receiver.dropped(), 1);
+        assert_eq!(receiver.recv().await.unwrap(), frames[1]);
+        assert_eq!(receiver.recv().await.unwrap(), frames[2]);
+    }
+
+    #[tokio::test]
+    async fn full_queues_can_disconnect_the_socket() {
+        let (outbox, receiver) = outbox(1, SlowConsumerPolicy::Disconnect);
+        let frames = frames(2);
+        assert!(outbox.push(frames[0].clone()));
+        assert!(tokio::time::timeout(Duration::from_millis(10), receiver.overflowed()).await.is_err());
+
+        assert!(!outbox.push(frames[1].clone()));
+        tokio::time::timeout(Duration::from_secs(1), receiver.overflowed()).await.unwrap();
+        assert_eq!(receiver.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn senders_wait_for_room_until_the_socket_is_gone() {
+        let (outbox, receiver) = outbox(1, SlowConsumerPolicy::default());
+        let frames = frames(3);
+        outbox.send(frames[0].clone()).await.unwrap();
+
+        let waiting = tokio::spawn({
+            let (outbox, frame) = (outbox.clone(), frames[1].clone());
+            async move { outbox.send(frame).await }
+        });
+        assert_eq!(receiver.recv().await.unwrap(), frames[0]);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), frames[1]);
+
+        drop(receiver);
+        assert!(!outbox.push(frames[2].clone()));
+        assert!(outbox.send(frames[2].clone()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn receivers_finish_once_every_outbox_is_gone() {
+        let (outbox, receiver) = outbox(2, SlowConsumerPolicy::default());
+        let frames = frames(1);
+        let other = outbox.clone();
+        outbox.push(frames[0].clone());
+        drop(outbox);
+        assert_eq!(receiver.recv().await.unwrap(), frames[0]);
+
+        drop(other);
+        assert!(tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await.unwrap().is_none());
+    }
+}
@@ -1,12 +1,19 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use chrono::{DateTime, Utc};
 use tokio::sync::RwLock;
-use crate::app::{check_signature, arc_rwlock_serde, HashMap, Deserialize, Serialize, Channel, CustomError};
-// use chrono::serde::ts_milliseconds::serialize as to_milli_ts;
-use chrono::serde::ts_milliseconds::deserialize as from_milli_ts;
+use crate::app::{is_cache_channel, Adapter, Cluster, CustomEvent, Frame, Metrics, check_signature, verify_signature, create_channel_auth, create_shared_secret, decode_master_key, create_body_md5, origin_matches, HashMap, Deserialize, Serialize, json, Channel, ChannelRegistry, CustomError, ProtocolError, ServerEvent, SignedInUser, SlowConsumerPolicy, UserSocket, Webhook, WebhookDispatcher, WebhookEvent};
+use chrono::serde::ts_seconds::deserialize as from_ts;
+
+/// Default number of idle seconds before the server probes a connection with `pusher:ping`.
+pub(crate) const DEFAULT_ACTIVITY_TIMEOUT: u8 = 120;
+
+/// Default number of seconds an HTTP API `auth_timestamp` may differ from the server clock.
+pub(crate) const DEFAULT_TIMESTAMP_WINDOW: u32 = 600;
 
 #[repr(C)]
-#[derive(Clone, Debug, Serialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Pusher {
     pub id: u32,
     pub key: String,
@@ -16,10 +23,32 @@ pub struct Pusher {
     pub path: Option<String>,
     pub capacity: Option<u32>,
     pub client_messages_enabled: Option<bool>,
+    /// Leaves the app out of `/metrics` when false.
     pub statistics_enabled: Option<bool>,
+    /// Allows `subscription_count` in channel queries, off unless enabled.
+    pub subscription_count_enabled: Option<bool>,
     pub allowed_origins: Option<Vec<String>>,
-    #[serde(with = "arc_rwlock_serde")]
-    pub(crate) channels: Arc<RwLock<HashMap<String, Channel>>>,
+    pub timestamp_window: Option<u32>,
+    pub activity_timeout: Option<u8>,
+    pub webhooks: Option<Vec<Webhook>>,
+    /// Base64 encoded 32 byte key from which the secrets of encrypted channels are derived.
+    pub encryption_master_key_base64: Option<String>,
+    /// What happens to events for a socket that does not keep up, dropping the newest by default.
+    pub slow_consumer_policy: Option<SlowConsumerPolicy>,
+    /// Largest `data` of an event in bytes, 10KB by default.
+    pub max_payload_size: Option<usize>,
+    #[serde(skip_deserializing)]
+    pub(crate) channels: ChannelRegistry,
+    #[serde(skip)]
+    pub(crate) users: Arc<RwLock<HashMap<String, HashMap<String, UserSocket>>>>,
+    #[serde(skip)]
+    pub(crate) webhook_dispatcher: WebhookDispatcher,
+    #[serde(skip)]
+    pub(crate) connections: Arc<AtomicUsize>,
+    #[serde(skip)]
+    pub(crate) cluster: Cluster,
+    #[serde(skip)]
+    pub(crate) metrics: Arc<Metrics>,
 }
 
 impl Pusher {
@@ -34,8 +63,20 @@ impl Pusher {
             capacity: None,
             client_messages_enabled: None,
             statistics_enabled: None,
+            subscription_count_enabled: None,
             allowed_origins: None,
-            channels: Arc::new(RwLock::new(HashMap::default())),
+            timestamp_window: None,
+            activity_timeout: None,
+            webhooks: None,
+            encryption_master_key_base64: None,
+            slow_consumer_policy: None,
+            max_payload_size: None,
+            channels: ChannelRegistry::default(),
+            users: Arc::new(RwLock::new(HashMap::default())),
+            webhook_dispatcher: WebhookDispatcher::default(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            cluster: Cluster::default(),
+            metrics: Arc::default(),
         }
     }
     #[allow(dead_code)]
@@ -59,6 +100,10 @@ impl Pusher {
         self.client_messages_enabled = Some(client_messages_enabled);
     }
     #[allow(dead_code)]
+    pub fn set_subscription_count_enabled(&mut self, subscription_count_enabled: bool) {
+        self.subscription_count_enabled = Some(subscription_count_enabled);
+    }
+    #[allow(dead_code)]
     pub fn set_statistics_enabled(&mut self, statistics_enabled: bool) {
         self.statistics_enabled = Some(statistics_enabled);
     }
@@ -66,40 +111,207 @@ impl Pusher {
     pub fn set_allowed_origins(&mut self, allowed_origins: Vec<String>) {
         self.allowed_origins = Some(allowed_origins);
     }
+    #[allow(dead_code)]
+    pub fn set_timestamp_window(&mut self, timestamp_window: u32) {
+        self.timestamp_window = Some(timestamp_window);
+    }
+    #[allow(dead_code)]
+    pub fn set_activity_timeout(&mut self, activity_timeout: u8) {
+        self.activity_timeout = Some(activity_timeout);
+    }
+    #[allow(dead_code)]
+    pub fn set_encryption_master_key_base64(&mut self, encryption_master_key_base64: &str) {
+        self.encryption_master_key_base64 = Some(encryption_master_key_base64.to_owned());
+    }
+    #[allow(dead_code)]
+    pub fn set_slow_consumer_policy(&mut self, slow_consumer_policy: SlowConsumerPolicy) {
+        self.slow_consumer_policy = Some(slow_consumer_policy);
+    }
+    #[allow(dead_code)]
+    pub fn set_max_payload_size(&mut self, max_payload_size: usize) {
+        self.max_payload_size = Some(max_payload_size);
+    }
+    #[allow(dead_code)]
+    pub fn set_webhooks(&mut self, webhooks: Vec<Webhook>) {
+        self.webhooks = Some(webhooks);
+    }
+    /// Builds the string to sign: the method and path followed by every query parameter
+    /// except `auth_signature`, with lowercased keys, sorted by key.
     #[inline(always)]
     fn prepare_auth_body(&self, query: &PusherQuery, path: &str) -> String {
-        let format = format!("{path}auth_key={auth_key}&auth_timestamp={timestamp}&auth_version={auth_version}",
-            path = path,
-            auth_key = self.key,
-            timestamp = query.auth_timestamp.timestamp_millis(),
-            auth_version = if query.auth_version == 1.0 { "1.0".to_string() } else { query.auth_version.to_string() }
-        );
+        let mut params = query.params.iter()
+            .map(|(key, value)| (key.to_lowercase(), value.as_str()))
+            .filter(|(key, _)| key != "auth_signature")
+            .collect::<Vec<(String, &str)>>();
+        params.sort();
 
-        match &query.body_md5 {
-            None => { format }
-            Some(body_md5) => { format!("{}&body_md5={}", format, body_md5) }
-        }
+        let params = params.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<String>>();
+        format!("{}{}", path, params.join("&"))
     }
     #[inline(always)]
     pub(crate) fn ensure_valid_signature(&self, query: &PusherQuery, path: &str) -> Result<Pusher, warp::Rejection> {
+        if query.auth_key != self.key {
+            return Err(warp::reject::custom(CustomError::AuthKeyMismatch));
+        }
+
+        let window = self.timestamp_window.unwrap_or(DEFAULT_TIMESTAMP_WINDOW) as i64;
+        if (Utc::now() - query.auth_timestamp).num_seconds().abs() > window {
+            return Err(warp::reject::custom(CustomError::AuthTimestampExpired));
+        }
+
         let auth_body = self.prepare_auth_body(query, path);
 
         check_signature(query.auth_signature.as_str(), self.secret.as_str(), auth_body.as_str()).map(move |_| self.clone())
     }
+    /// A non-empty request body must be accompanied by a matching `body_md5` query parameter.
     #[inline(always)]
-    pub(crate) async fn get_channel(&self, name: String) -> Result<Channel, warp::Rejection> {
-        if let Some(channel) = self.channels.read().await.get(&name) {
-            return Ok(channel.clone());
+    pub(crate) fn ensure_valid_body(&self, query: &PusherQuery, body: &[u8]) -> Result<(), warp::Rejection> {
+        match &query.body_md5 {
+            None if body.is_empty() => Ok(()),
+            Some(body_md5) if body_md5.eq_ignore_ascii_case(&create_body_md5(body)) => Ok(()),
+            _ => Err(warp::reject::custom(CustomError::BodyMd5Mismatch)),
+        }
+    }
+    #[inline(always)]
+    pub(crate) fn ensure_valid_channel_auth(&self, socket_id: &str, channel: &str, auth: Option<&str>, channel_data: Option<&serde_json::Value>) -> Result<(), CustomError> {
+        self.verify_channel_auth(socket_id, channel, auth, channel_data).inspect_err(|_| self.metrics.auth_failed("websocket"))
+    }
+    fn verify_channel_auth(&self, socket_id: &str, channel: &str, auth: Option<&str>, channel_data: Option<&serde_json::Value>) -> Result<(), CustomError> {
+        let (auth_key, signature) = auth.and_then(|auth| auth.split_once(':')).ok_or(CustomError::AuthSignatureError)?;
+        if auth_key != self.key {
+            return Err(CustomError::AuthKeyMismatch);
+        }
+
+        let to_sign = match channel_data {
+            None => format!("{}:{}", socket_id, channel),
+            Some(serde_json::Value::String(channel_data)) => format!("{}:{}:{}", socket_id, channel, channel_data),
+            Some(channel_data) => format!("{}:{}:{}", socket_id, channel, channel_data),
+        };
+
+        verify_signature(signature, self.secret.as_str(), to_sign.as_str())
+    }
+    /// Body of a channel authorization response, for the auth endpoint of an application embedding
+    /// this crate to return to its client once it has authenticated the user.
+    /// Encrypted channels also get their `shared_secret` when the app has a master key.
+    pub fn channel_auth_response(&self, socket_id: &str, channel: &str, channel_data: Option<&str>) -> serde_json::Value {
+        let mut response = HashMap::default();
+        let to_sign = match channel_data {
+            Some(channel_data) => {
+                response.insert("channel_data", channel_data.to_owned());
+                format!("{}:{}:{}", socket_id, channel, channel_data)
+            }
+            None => format!("{}:{}", socket_id, channel),
+        };
+        create_channel_auth(&mut response, self.key.as_str(), self.secret.as_str(), to_sign.as_str());
+
+        let master_key = self.encryption_master_key_base64.as_deref().and_then(decode_master_key);
+        if let (true, Some(master_key)) = (Channel::from(channel.to_owned()).is_encrypted(), master_key) {
+            response.insert("shared_secret", create_shared_secret(channel, &master_key));
+        }
+        json!(response)
+    }
+    /// Verifies the `auth` of a `pusher:signin`, signed over `{socket_id}::user::{user_data}`.
+    pub(crate) fn ensure_valid_signin(&self, socket_id: &str, auth: Option<&str>, user_data: &str) -> Result<SignedInUser, CustomError> {
+        self.verify_signin(socket_id, auth, user_data).inspect_err(|_| self.metrics.auth_failed("websocket"))?;
+        SignedInUser::from_user_data(user_data)
+    }
+    fn verify_signin(&self, socket_id: &str, auth: Option<&str>, user_data: &str) -> Result<(), CustomError> {
+        let (auth_key, signature) = auth.and_then(|auth| auth.split_once(':')).ok_or(CustomError::AuthSignatureError)?;
+        if auth_key != self.key {
+            return Err(CustomError::AuthKeyMismatch);
+        }
+
+        verify_signature(signature, self.secret.as_str(), format!("{}::user::{}", socket_id, user_data).as_str())
+    }
+    /// Without configured origins every origin is allowed, otherwise the request must carry
+    /// an `Origin` matching one of the patterns.
+    #[inline(always)]
+    pub(crate) fn is_origin_allowed(&self, origin: Option<&str>) -> bool {
+        match &self.allowed_origins {
+            None => true,
+            Some(allowed_origins) if allowed_origins.is_empty() => true,
+            Some(allowed_origins) => origin.is_some_and(|origin| allowed_origins.iter().any(|pattern| origin_matches(pattern, origin))),
         }
-        Err(warp::reject::custom(CustomError::ChannelNotFound))
+    }
+    /// Reserves a connection slot, or `None` when the app is at capacity.
+    /// The slot is released when the returned guard is dropped.
+    #[inline(always)]
+    pub(crate) fn try_connect(&self) -> Option<ConnectionGuard> {
+        let capacity = self.capacity.map(|capacity| capacity as usize).unwrap_or(usize::MAX);
+        self.connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| (count < capacity).then(|| count + 1))
+            .ok()
+            .map(|_| ConnectionGuard { connections: self.connections.clone() })
+    }
+    #[inline(always)]
+    pub(crate) fn connections_count(&self) -> usize {
+        self.connections.load(Ordering::Acquire)
     }
     #[inline(always)]
-    pub(crate) async fn get_channels(&self) -> Result<HashMap<String, Channel>, warp::Rejection> {
-        let channels = self.channels.read().await;
-        if channels.len() == 0 {
-            return Ok(channels.clone());
+    pub(crate) fn emit_webhook(&self, event: WebhookEvent) {
+        match &self.webhooks {
+            Some(webhooks) if webhooks.iter().any(|webhook| webhook.accepts(&event)) => {
+                self.webhook_dispatcher.send(self.key.as_str(), self.secret.as_str(), webhooks, event)
+            }
+            _ => {}
         }
-        Err(warp::reject::custom(CustomError::ChannelsNotFound))
+    }
+    pub(crate) async fn add_user_socket(&self, user_id: &str, socket_id: &str, socket: UserSocket) {
+        self.users.write().await.entry(user_id.to_owned()).or_default().insert(socket_id.to_owned(), socket);
+    }
+    pub(crate) async fn remove_user_socket(&self, user_id: &str, socket_id: &str) {
+        let mut users = self.users.write().await;
+        if let Some(sockets) = users.get_mut(user_id) {
+            sockets.remove(socket_id);
+            if sockets.is_empty() {
+                users.remove(user_id);
+            }
+        }
+    }
+    /// Sends `channel_occupied` and `channel_vacated` webhooks as the channels change, for as long as the app lives.
+    pub(crate) fn relay_channel_webhooks(&self) {
+        if self.webhooks.as_ref().is_none_or(Vec::is_empty) {
+            return;
+        }
+        let mut transitions = self.channels.transitions();
+        let pusher = self.clone();
+        tokio::spawn(async move {
+            while let Some(transition) = transitions.recv().await {
+                // Only the first node to occupy a channel and the last one to vacate it report it.
+                if !pusher.cluster.is_occupied_elsewhere(transition.channel()) {
+                    pusher.emit_webhook(transition.into());
+                }
+            }
+        });
+    }
+    /// Delivers the event to the sockets of this node subscribed to its channel, except the given one.
+    pub(crate) async fn publish_local(&self, event: CustomEvent, except: Option<&str>) {
+        self.channels.remember(&event);
+        let name = event.channel.to_owned();
+        let event = ServerEvent::ChannelEvent(event);
+        let delivery = self.channels.inspect(&name, |channel| channel.map(|channel| channel.delivery(&event, except)));
+        if let Some(delivery) = delivery {
+            self.metrics.messages_dropped(delivery.send());
+        }
+    }
+    /// Delivers the event to every socket of this node signed in as the user.
+    pub(crate) async fn publish_to_user(&self, user_id: &str, event: ServerEvent) {
+        if let Some(sockets) = self.users.read().await.get(user_id) {
+            let frame = Frame::from(event);
+            let dropped = sockets.values().filter(|socket| !socket.sender.push(frame.clone())).count();
+            self.metrics.messages_dropped(dropped);
+        }
+    }
+    /// Closes every socket of this node signed in as the user.
+    pub(crate) async fn terminate_user_connections(&self, user_id: &str) {
+        if let Some(sockets) = self.users.read().await.get(user_id) {
+            sockets.values().for_each(|socket| socket.terminate.notify_one());
+        }
+    }
+    #[inline(always)]
+    pub(crate) async fn get_channel(&self, name: String) -> Result<Channel, warp::Rejection> {
+        self.channels.inspect(&name, |channel| channel.cloned())
+            .ok_or_else(|| warp::reject::custom(CustomError::ChannelNotFound))
     }
 }
 
@@ -117,10 +329,26 @@ impl PusherServer {
             apps: map
         }
     }
-    #[allow(dead_code)]
     pub fn add(&mut self, app: Pusher) {
         self.apps.insert(app.key.to_owned(), app);
     }
+    /// Relays events and membership of every app to the other nodes sharing the adapter.
+    pub async fn join_cluster(&mut self, adapter: Arc<dyn Adapter>) -> Result<(), crate::Error> {
+        for app in self.apps.values_mut() {
+            app.cluster = Cluster::join(app, adapter.clone()).await?;
+        }
+        Ok(())
+    }
+    /// Starts sending the channel webhooks of every app and expiring their cached events.
+    pub(crate) fn watch_channels(&self) {
+        for pusher in self.apps.values() {
+            pusher.relay_channel_webhooks();
+            pusher.channels.sweep_expired_events();
+        }
+    }
+    pub(crate) fn apps(&self) -> impl Iterator<Item = &Pusher> {
+        self.apps.values()
+    }
     #[allow(dead_code)]
     pub(crate) fn remove(&mut self, key: &str) {
         self.apps.remove(key);
@@ -154,60 +382,243 @@ impl PusherServer {
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+    connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub(crate) struct ConnectionInfo {
     pub socket_id: String,
     pub activity_timeout: u8,
 }
 
+/// Protocol versions of the Pusher websocket protocol this server speaks.
+pub(crate) const SUPPORTED_PROTOCOLS: std::ops::RangeInclusive<u8> = 5..=7;
+
+/// Parameters client libraries append to the websocket url, as in
+/// `/app/{key}?protocol=7&client=js&version=8.4.0&flash=false`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub(crate) struct ConnectionQuery {
+    pub protocol: Option<String>,
+    pub client: Option<String>,
+    pub version: Option<String>,
+    pub flash: Option<String>,
+}
+
+impl ConnectionQuery {
+    /// The protocol version the client asked for, when this server supports it.
+    pub(crate) fn protocol(&self) -> Result<u8, ProtocolError> {
+        let protocol = self.protocol.as_deref().ok_or(ProtocolError::NoProtocolVersion)?;
+        let version = protocol.parse::<u8>().map_err(|_| ProtocolError::InvalidVersionFormat)?;
+        if !SUPPORTED_PROTOCOLS.contains(&version) {
+            return Err(ProtocolError::UnsupportedProtocolVersion(protocol.to_owned()));
+        }
+        Ok(version)
+    }
+
+    /// Client library and version for diagnostics, e.g. `js 8.4.0`.
+    pub(crate) fn client_description(&self) -> String {
+        let mut description = format!(
+            "{} {}",
+            self.client.as_deref().unwrap_or("unknown"),
+            self.version.as_deref().unwrap_or("unknown"),
+        );
+        if self.flash.as_deref() == Some("true") {
+            description.push_str(" (flash)");
+        }
+        description
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct PusherQuery {
     pub auth_key: String,
-    #[serde(deserialize_with = "from_milli_ts")]
+    #[serde(deserialize_with = "from_ts")]
     pub auth_timestamp: DateTime<Utc>,
-    pub auth_version: f32,
+    pub auth_version: String,
     pub body_md5: Option<String>,
     pub auth_signature: String,
     pub info: Option<InfoQueryPram>,
     pub filter_by_prefix: Option<String>,
+    /// Every query parameter as received, used to build the string to sign.
+    #[serde(skip)]
+    pub params: Vec<(String, String)>,
 }
 
 impl PusherQuery {
-    pub(crate) fn is_channel_presence(&self) -> bool {
-        if let Some(info) = &self.info {
-            if let Some(filter_by_prefix) = &self.filter_by_prefix {
-                if info.user_count && filter_by_prefix.starts_with("presence-") {
-                    return true;
-                }
-            }
+    /// Checks the requested `info` attributes are available for the channel, or for the
+    /// channels matching `filter_by_prefix` when listing.
+    pub(crate) fn ensure_valid_info(&self, pusher: &Pusher, channel: Option<&str>) -> Result<(), warp::Rejection> {
+        let info = match &self.info {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+        if info.user_count && !channel.is_some_and(|channel| channel.starts_with("presence-")) {
+            return Err(warp::reject::custom(CustomError::UserCountNotPresence));
+        }
+        if info.subscription_count && !pusher.subscription_count_enabled.unwrap_or(false) {
+            return Err(warp::reject::custom(CustomError::SubscriptionCountDisabled));
+        }
+        if info.cache && !channel.is_some_and(is_cache_channel) {
+            return Err(warp::reject::custom(CustomError::CacheNotCacheChannel));
         }
-        false
+        Ok(())
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Deserialize)]
+/// Attributes requested through the comma separated `info` query parameter.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "String")]
 pub(crate) struct InfoQueryPram {
     pub user_count: bool,
     pub subscription_count: bool,
+    pub cache: bool,
 }
 
-impl std::str::FromStr for InfoQueryPram {
-    type Err = std::string::ParseError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let text: Vec<&str> = s.split(',').collect();
-        let mut user_count = false;
-        let mut subscription_count = false;
-        for t in text {
-            if !user_count {
-                user_count = t == "user_count";
-            }
-            if !subscription_count {
-                subscription_count = t == "subscription_count";
+impl From<String> for InfoQueryPram {
+    fn from(s: String) -> Self {
+        let mut info = InfoQueryPram::default();
+        for attribute in s.split(',').map(str::trim) {
+            match attribute {
+                "user_count" => info.user_count = true,
+                "subscription_count" => info.subscription_count = true,
+                "cache" => info.cache = true,
+                _ => {}
             }
         }
-        Ok(InfoQueryPram { user_count, subscription_count })
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use crate::app::{create_auth_signature, create_body_md5, CustomError, Pusher, PusherQuery};
+
+    const DOC_BODY: &str = r#"{"name":"foo","channels":["project-3"],"data":"{\"some\":\"data\"}"}"#;
+
+    fn query(params: &[(&str, &str)]) -> PusherQuery {
+        let get = |name: &str| params.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string());
+        PusherQuery {
+            auth_key: get("auth_key").unwrap(),
+            auth_timestamp: Utc.timestamp_opt(get("auth_timestamp").unwrap().parse().unwrap(), 0).unwrap(),
+            auth_version: get("auth_version").unwrap(),
+            body_md5: get("body_md5"),
+            auth_signature: get("auth_signature").unwrap(),
+            info: None,
+            filter_by_prefix: get("filter_by_prefix"),
+            params: params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    fn rejection(result: Result<impl std::fmt::Debug, warp::Rejection>) -> String {
+        result.unwrap_err().find::<CustomError>().map(|err| format!("{:?}", err)).unwrap_or_default()
+    }
+
+    #[test]
+    fn accepts_pusher_documentation_vector() {
+        let mut pusher = Pusher::new(3, "278d425bdf160c739803", "7ad3773142a6692b25b8");
+        pusher.set_timestamp_window(u32::MAX);
+        let query = query(&[
+            ("body_md5", "ec365a775a4cd0599faeb73354201b6f"),
+            ("auth_version", "1.0"),
+            ("auth_key", "278d425bdf160c739803"),
+            ("auth_timestamp", "1353088179"),
+            ("auth_signature", "da454824c97ba181a32ccc17a72625ba02771f50b50e1e7430e47a1f3f457e6c"),
+        ]);
+
+        assert_eq!(create_body_md5(DOC_BODY.as_bytes()), "ec365a775a4cd0599faeb73354201b6f");
+        assert!(pusher.ensure_valid_signature(&query, "POST\n/apps/3/events\n").is_ok());
+        assert!(pusher.ensure_valid_body(&query, DOC_BODY.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn signs_every_param_sorted_and_lowercased() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let timestamp = Utc::now().timestamp().to_string();
+        let to_sign = format!("GET\n/apps/1/channels\nauth_key=key&auth_timestamp={}&auth_version=1.0&filter_by_prefix=presence-&info=user_count", timestamp);
+        let signature = create_auth_signature(&to_sign, "secret");
+        let params = [
+            ("info", "user_count"),
+            ("auth_signature", signature.as_str()),
+            ("Filter_By_Prefix", "presence-"),
+            ("auth_timestamp", timestamp.as_str()),
+            ("auth_key", "key"),
+            ("auth_version", "1.0"),
+        ];
+
+        assert!(pusher.ensure_valid_signature(&query(&params), "GET\n/apps/1/channels\n").is_ok());
+
+        let mut tampered = params;
+        tampered[0] = ("info", "subscription_count");
+        assert_eq!(rejection(pusher.ensure_valid_signature(&query(&tampered), "GET\n/apps/1/channels\n")), "AuthKeyMismatch");
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_window_and_foreign_keys() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        let signed = |key: &str, timestamp: i64| {
+            let timestamp = timestamp.to_string();
+            let to_sign = format!("GET\n/apps/1/channels\nauth_key={}&auth_timestamp={}&auth_version=1.0", key, timestamp);
+            let signature = create_auth_signature(&to_sign, "secret");
+            query(&[("auth_key", key), ("auth_timestamp", &timestamp), ("auth_version", "1.0"), ("auth_signature", &signature)])
+        };
+        let now = Utc::now();
+
+        assert!(pusher.ensure_valid_signature(&signed("key", (now - Duration::seconds(590)).timestamp()), "GET\n/apps/1/channels\n").is_ok());
+        assert_eq!(rejection(pusher.ensure_valid_signature(&signed("key", (now - Duration::seconds(610)).timestamp()), "GET\n/apps/1/channels\n")), "AuthTimestampExpired");
+        assert_eq!(rejection(pusher.ensure_valid_signature(&signed("key", (now + Duration::seconds(610)).timestamp()), "GET\n/apps/1/channels\n")), "AuthTimestampExpired");
+        assert_eq!(rejection(pusher.ensure_valid_signature(&signed("other", now.timestamp()), "GET\n/apps/1/channels\n")), "AuthKeyMismatch");
+
+        pusher.set_timestamp_window(30);
+        assert_eq!(rejection(pusher.ensure_valid_signature(&signed("key", (now - Duration::seconds(60)).timestamp()), "GET\n/apps/1/channels\n")), "AuthTimestampExpired");
+    }
+
+    #[test]
+    fn rejects_body_without_matching_md5() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let mut query = query(&[("auth_key", "key"), ("auth_timestamp", "0"), ("auth_version", "1.0"), ("auth_signature", "")]);
+
+        assert!(pusher.ensure_valid_body(&query, b"").is_ok());
+        assert_eq!(rejection(pusher.ensure_valid_body(&query, DOC_BODY.as_bytes())), "BodyMd5Mismatch");
+
+        query.body_md5 = Some(create_body_md5(b"{}"));
+        assert_eq!(rejection(pusher.ensure_valid_body(&query, DOC_BODY.as_bytes())), "BodyMd5Mismatch");
+        assert!(pusher.ensure_valid_body(&query, b"{}").is_ok());
+    }
+
+    #[tokio::test]
+    async fn info_is_parsed_from_a_comma_separated_list() {
+        let filter = warp::query::<PusherQuery>();
+        let uri = "/?auth_key=key&auth_timestamp=1353088179&auth_version=1.0&auth_signature=x&info=user_count,subscription_count";
+
+        let query = warp::test::request().path(uri).filter(&filter).await.unwrap();
+        let info = query.info.unwrap();
+        assert!(info.user_count && info.subscription_count && !info.cache);
+
+        let query = warp::test::request().path("/?auth_key=key&auth_timestamp=1353088179&auth_version=1.0&auth_signature=x&info=").filter(&filter).await.unwrap();
+        assert!(!query.info.unwrap().user_count);
+    }
+
+    #[test]
+    fn encrypted_channel_auth_includes_shared_secret() {
+        use base64::Engine;
+        let mut pusher = Pusher::new(1, "key", "secret");
+        let response = pusher.channel_auth_response("1.1", "private-encrypted-room", None);
+        assert_eq!(response["auth"], format!("key:{}", create_auth_signature("1.1:private-encrypted-room", "secret")));
+        assert!(response.get("shared_secret").is_none());
+
+        pusher.set_encryption_master_key_base64(&base64::engine::general_purpose::STANDARD.encode([7u8; 32]));
+        let response = pusher.channel_auth_response("1.1", "private-encrypted-room", None);
+        assert_eq!(response["shared_secret"], crate::app::create_shared_secret("private-encrypted-room", &[7u8; 32]));
+        assert!(pusher.channel_auth_response("1.1", "private-room", None).get("shared_secret").is_none());
     }
 }
@@ -0,0 +1,50 @@
+//! Helpers shared by the unit tests.
+
+use std::sync::Arc;
+use std::time::Duration;
+use warp::Reply;
+use crate::app::{outbox, Cluster, MemoryAdapter, OutboxReceiver, Pusher, PusherQuery, SlowConsumerPolicy, Subscription};
+
+/// A query that passed signature checks, asking for nothing in particular.
+pub(crate) fn query() -> PusherQuery {
+    PusherQuery {
+        auth_key: "key".to_owned(),
+        auth_timestamp: chrono::Utc::now(),
+        auth_version: "1.0".to_owned(),
+        body_md5: None,
+        auth_signature: String::new(),
+        info: None,
+        filter_by_prefix: None,
+        params: Vec::new(),
+    }
+}
+
+/// The JSON body of a handler's reply.
+pub(crate) async fn body(reply: warp::reply::Json) -> serde_json::Value {
+    let bytes = warp::hyper::body::to_bytes(reply.into_response().into_body()).await.unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+/// Subscribes a socket to the channel of this node, as the member `user_id` if given.
+pub(crate) async fn subscribe(pusher: &Pusher, channel: &str, socket_id: &str, user_id: Option<&str>) -> OutboxReceiver {
+    let (sender, receiver) = outbox(16, SlowConsumerPolicy::default());
+    pusher.channels.entry(channel, |entry| entry
+        .add_subscription(socket_id, Subscription { sender, data: None, user_id: user_id.map(str::to_owned) }));
+    receiver
+}
+
+/// The app of a new node joining the cluster of the adapter.
+pub(crate) async fn node(adapter: &MemoryAdapter) -> Pusher {
+    let mut pusher = Pusher::new(1, "key", "secret");
+    pusher.cluster = Cluster::join(&pusher, Arc::new(adapter.clone())).await.unwrap();
+    pusher
+}
+
+/// Waits for the condition to hold, such as another node having heard of a change.
+pub(crate) async fn until(condition: impl Fn() -> bool) {
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }).await.expect("condition never held");
+}
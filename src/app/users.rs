@@ -0,0 +1,40 @@
+use std::sync::Arc;
+use tokio::sync::Notify;
+use crate::app::{Outbox, Serialize, CustomError};
+
+/// Channel name the REST API publishes to when an event is meant for every socket of a user.
+pub(crate) const SERVER_TO_USER_PREFIX: &str = "#server-to-user-";
+
+/// A socket signed in as a user, with the means to deliver events to it or close it.
+#[derive(Clone, Debug)]
+pub(crate) struct UserSocket {
+    pub sender: Outbox,
+    pub terminate: Arc<Notify>,
+}
+
+/// The user a socket signed in as, taken from the `user_data` of `pusher:signin`.
+#[derive(Clone, Debug)]
+pub(crate) struct SignedInUser {
+    pub id: String,
+    pub user_data: String,
+}
+
+impl SignedInUser {
+    /// Parses the JSON encoded `user_data`, which must carry a non empty string `id`.
+    pub(crate) fn from_user_data(user_data: &str) -> Result<SignedInUser, CustomError> {
+        let data: serde_json::Value = serde_json::from_str(user_data).map_err(|_| CustomError::InvalidUserData)?;
+        match data.get("id") {
+            Some(serde_json::Value::String(id)) if !id.is_empty() => Ok(SignedInUser {
+                id: id.to_owned(),
+                user_data: user_data.to_owned(),
+            }),
+            _ => Err(CustomError::InvalidUserData),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SigninInfo {
+    pub user_data: String,
+}
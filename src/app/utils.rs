@@ -1,23 +1,36 @@
+use std::sync::LazyLock;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use hex::{FromHex, ToHex};
-use sha2::Sha256;
+use regex::Regex;
+use sha2::{Digest, Sha256};
 use hmac::{Hmac, Mac};
-use crate::app::CustomError;
+use crate::app::{CustomError, SERVER_TO_USER_PREFIX};
 
 type HmacSha256 = Hmac<Sha256>;
 
-#[allow(dead_code)]
+/// Largest REST API request body, which bounds the payload size an app may allow.
+pub(crate) const MAX_BODY_SIZE: usize = 16 * 1024;
+/// Default largest `data` of an event in bytes, as on Pusher.
+pub(crate) const DEFAULT_MAX_PAYLOAD_SIZE: usize = 10 * 1024;
+/// Longest channel or event name, in characters.
+pub(crate) const MAX_NAME_LENGTH: usize = 200;
+/// Most channels one event can be triggered on.
+pub(crate) const MAX_EVENT_CHANNELS: usize = 100;
+
+static CHANNEL_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[-a-zA-Z0-9_=@,.;]+$").unwrap());
+
 #[inline(always)]
-pub(crate) fn create_body_md5(body: &str) -> String {
+pub(crate) fn create_body_md5(body: &[u8]) -> String {
     use md5::{Md5, Digest};
 
     let mut sh = Md5::new();
-    sh.update(body.as_bytes());
+    sh.update(body);
     sh.finalize().encode_hex()
 }
 
-#[allow(dead_code)]
 #[inline(always)]
-pub(crate) fn create_channel_auth<'a>(auth_map: &mut hashbrown::HashMap<&'a str, String>, key: &str, secret: &str, to_sign: &str) {
+pub(crate) fn create_channel_auth(auth_map: &mut hashbrown::HashMap<&str, String>, key: &str, secret: &str, to_sign: &str) {
     let auth_signature = create_auth_signature(to_sign, secret);
     let auth_string = format!("{}:{}", key, auth_signature);
     auth_map.insert("auth", auth_string);
@@ -25,14 +38,19 @@ pub(crate) fn create_channel_auth<'a>(auth_map: &mut hashbrown::HashMap<&'a str,
 
 #[inline(always)]
 pub(crate) fn check_signature(signature: &str, secret: &str, body: &str) -> Result<(), warp::Rejection> {
+    verify_signature(signature, secret, body).map_err(warp::reject::custom)
+}
+
+#[inline(always)]
+pub(crate) fn verify_signature(signature: &str, secret: &str, body: &str) -> Result<(), CustomError> {
     let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
     mac.update(body.as_bytes());
 
     if let Ok(decoded_signature) = Vec::from_hex(signature) {
-        if let Ok(_) = mac.verify_slice(&decoded_signature[..]) {
+        if mac.verify_slice(&decoded_signature[..]).is_ok() {
             Ok(())
-        } else { Err(warp::reject::custom(CustomError::AuthKeyMismatch)) }
-    } else { Err(warp::reject::custom(CustomError::AuthSignatureError)) }
+        } else { Err(CustomError::AuthKeyMismatch) }
+    } else { Err(CustomError::AuthSignatureError) }
 }
 
 #[allow(dead_code)]
@@ -43,6 +61,69 @@ pub(crate) fn create_auth_signature(to_sign: &str, secret: &str) -> String {
     mac.finalize().into_bytes().encode_hex()
 }
 
+/// Secret shared by the subscribers of a `private-encrypted-` channel, derived as
+/// `base64(sha256(channel_name + master_key))` like the Pusher server libraries do.
+pub(crate) fn create_shared_secret(channel: &str, master_key: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(channel.as_bytes());
+    hasher.update(master_key);
+    BASE64.encode(hasher.finalize())
+}
+
+pub(crate) fn decode_master_key(master_key_base64: &str) -> Option<Vec<u8>> {
+    BASE64.decode(master_key_base64).ok().filter(|key| key.len() == 32)
+}
+
+/// Whether the event data is a NaCl secretbox envelope: a JSON object holding a base64
+/// 24 byte `nonce` and a base64 `ciphertext` long enough to carry the authentication tag.
+pub(crate) fn is_encrypted_envelope(data: &str) -> bool {
+    let envelope: serde_json::Value = match serde_json::from_str(data) {
+        Ok(envelope) => envelope,
+        Err(_) => return false,
+    };
+    let decode = |field: &str| envelope.get(field).and_then(|value| value.as_str()).and_then(|value| BASE64.decode(value).ok());
+
+    matches!((decode("nonce"), decode("ciphertext")), (Some(nonce), Some(ciphertext)) if nonce.len() == 24 && ciphertext.len() >= 16)
+}
+
+/// Matches an origin such as `https://app.example.com` against an allowed origin pattern.
+/// A lone `*` allows every origin, otherwise `*` stands for any run of characters within
+/// the host or port, e.g. `https://*.example.com` or `http://localhost:*`.
+pub(crate) fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+
+    let pattern = pattern.trim_end_matches('/').to_ascii_lowercase();
+    let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+
+    let mut rest = match origin.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        let index = if parts.peek().is_none() {
+            match rest.len().checked_sub(part.len()) {
+                Some(index) if rest.ends_with(part) => index,
+                _ => return false,
+            }
+        } else {
+            match rest.find(part) {
+                Some(index) => index,
+                None => return false,
+            }
+        };
+        if rest[..index].contains('/') {
+            return false;
+        }
+        rest = &rest[index + part.len()..];
+    }
+    rest.is_empty()
+}
+
 #[inline(always)]
 pub(crate) fn generate_socket_id() -> String {
     use rand::distributions::{Distribution, Uniform};
@@ -55,22 +136,123 @@ pub(crate) fn generate_socket_id() -> String {
     format!("{}.{}", p1, p2)
 }
 
-#[allow(dead_code)]
-#[inline(always)]
-pub(crate) fn validate_channels(channels: &Vec<String>) -> Result<bool, String> {
-    if channels.len() > 10 {
-        return Err("Cannot trigger on more than 10 channels".to_string());
+/// A channel a client can subscribe to: at most 200 characters out of `-a-zA-Z0-9_=@,.;`.
+pub(crate) fn validate_channel_name(name: &str) -> Result<(), CustomError> {
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(CustomError::ChannelNameTooLong);
     }
+    if !CHANNEL_NAME.is_match(name) {
+        return Err(CustomError::ChannelNameInvalid);
+    }
+    Ok(())
+}
 
-    let channel_regex = regex::Regex::new(r"^[-a-zA-Z0-9_=@,.;]+$").unwrap();
-
+/// The channels of a REST API event, which may also name the `#server-to-user-` channel of a user.
+pub(crate) fn validate_event_channels<'a>(channels: impl ExactSizeIterator<Item = &'a str>) -> Result<(), CustomError> {
+    if channels.len() > MAX_EVENT_CHANNELS {
+        return Err(CustomError::TooManyChannels);
+    }
     for channel in channels {
-        if channel.len() > 200 {
-            return Err("Channel names must be under 200 characters".to_string());
-        }
-        if !channel_regex.is_match(channel) {
-            return Err("Channels must be formatted as such: ^[-a-zA-Z0-9_=@,.;]+$".to_string());
+        match channel.strip_prefix(SERVER_TO_USER_PREFIX) {
+            Some("") => return Err(CustomError::ChannelNameInvalid),
+            Some(_) if channel.chars().count() > MAX_NAME_LENGTH => return Err(CustomError::ChannelNameTooLong),
+            Some(_) => {}
+            None => validate_channel_name(channel)?,
         }
     }
-    Ok(true)
+    Ok(())
+}
+
+pub(crate) fn validate_event_name(name: &str) -> Result<(), CustomError> {
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(CustomError::EventNameTooLong);
+    }
+    Ok(())
+}
+
+/// The size in bytes of the `data` of an event against the app's limit.
+pub(crate) fn validate_payload_size(data: &str, max_payload_size: usize) -> Result<(), CustomError> {
+    if data.len() > max_payload_size {
+        return Err(CustomError::PayloadTooLarge);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use crate::app::{create_shared_secret, is_encrypted_envelope, json, origin_matches, validate_channel_name, validate_event_channels,
+                     validate_event_name, validate_payload_size, CustomError};
+
+    #[test]
+    fn matches_exact_and_wildcard_origins() {
+        assert!(origin_matches("https://example.com", "https://example.com"));
+        assert!(origin_matches("https://example.com/", "HTTPS://Example.com"));
+        assert!(origin_matches("*", "http://localhost:3000"));
+        assert!(origin_matches("https://*.example.com", "https://app.example.com"));
+        assert!(origin_matches("https://*.example.com", "https://a.b.example.com"));
+        assert!(origin_matches("http://localhost:*", "http://localhost:8080"));
+
+        assert!(!origin_matches("https://example.com", "http://example.com"));
+        assert!(!origin_matches("https://example.com", "https://example.com.evil.io"));
+        assert!(!origin_matches("https://*.example.com", "https://example.com"));
+        assert!(!origin_matches("https://*.example.com", "https://app.example.com.evil.io"));
+        assert!(!origin_matches("https://*.example.com", "https://evil.io/.example.com"));
+    }
+
+    #[test]
+    fn shared_secret_hashes_channel_and_master_key() {
+        use sha2::{Digest, Sha256};
+        let master_key = [7u8; 32];
+
+        let secret = BASE64.decode(create_shared_secret("private-encrypted-room", &master_key)).unwrap();
+        let expected = Sha256::digest([b"private-encrypted-room".as_slice(), &master_key].concat());
+        assert_eq!(secret, expected.as_slice());
+        assert_ne!(create_shared_secret("private-encrypted-other", &master_key), create_shared_secret("private-encrypted-room", &master_key));
+    }
+
+    #[test]
+    fn recognizes_secretbox_envelopes() {
+        let envelope = |nonce: usize, ciphertext: usize| json!({"nonce": BASE64.encode(vec![1u8; nonce]), "ciphertext": BASE64.encode(vec![2u8; ciphertext])}).to_string();
+
+        assert!(is_encrypted_envelope(&envelope(24, 40)));
+        assert!(!is_encrypted_envelope(&envelope(12, 40)));
+        assert!(!is_encrypted_envelope(&envelope(24, 8)));
+        assert!(!is_encrypted_envelope(r#"{"message":"hello"}"#));
+        assert!(!is_encrypted_envelope(r#"{"nonce":"not base64!","ciphertext":"AAAA"}"#));
+        assert!(!is_encrypted_envelope("hello"));
+    }
+
+    #[test]
+    fn validates_channel_names() {
+        assert!(validate_channel_name("presence-room_1=a@b,c.d;e").is_ok());
+        assert!(matches!(validate_channel_name(""), Err(CustomError::ChannelNameInvalid)));
+        assert!(matches!(validate_channel_name("room 1"), Err(CustomError::ChannelNameInvalid)));
+        assert!(matches!(validate_channel_name("#server-to-user-1"), Err(CustomError::ChannelNameInvalid)));
+        assert!(validate_channel_name(&"a".repeat(200)).is_ok());
+        assert!(matches!(validate_channel_name(&"a".repeat(201)), Err(CustomError::ChannelNameTooLong)));
+    }
+
+    #[test]
+    fn validates_event_channels() {
+        let channels = (0..101).map(|index| index.to_string()).collect::<Vec<_>>();
+        assert!(validate_event_channels(channels[..100].iter().map(String::as_str)).is_ok());
+        assert!(matches!(validate_event_channels(channels.iter().map(String::as_str)), Err(CustomError::TooManyChannels)));
+
+        assert!(validate_event_channels(["room", "#server-to-user-1"].into_iter()).is_ok());
+        assert!(matches!(validate_event_channels(["#server-to-user-"].into_iter()), Err(CustomError::ChannelNameInvalid)));
+        assert!(matches!(validate_event_channels(["room", "bad room"].into_iter()), Err(CustomError::ChannelNameInvalid)));
+        let long_user = format!("#server-to-user-{}", "1".repeat(200));
+        assert!(matches!(validate_event_channels([long_user.as_str()].into_iter()), Err(CustomError::ChannelNameTooLong)));
+    }
+
+    #[test]
+    fn validates_event_names_and_payload_sizes() {
+        assert!(validate_event_name(&"e".repeat(200)).is_ok());
+        assert!(matches!(validate_event_name(&"e".repeat(201)), Err(CustomError::EventNameTooLong)));
+
+        assert!(validate_payload_size(&"x".repeat(10), 10).is_ok());
+        assert!(matches!(validate_payload_size("é".repeat(6).as_str(), 10), Err(CustomError::PayloadTooLarge)));
+    }
 }
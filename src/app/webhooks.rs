@@ -0,0 +1,343 @@
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::app::{create_auth_signature, ChannelTransition, Deserialize, Serialize};
+
+/// Events arriving within this window are delivered together in one request.
+const BATCH_WINDOW: Duration = Duration::from_millis(50);
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Requests taking longer fail, and are retried like any other failure.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Batches waiting for a url still busy with earlier ones. Beyond that the endpoint is not
+/// keeping up and further batches are dropped.
+const PENDING_BATCHES: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    /// Names of the events sent to this url, every event when unset.
+    pub event_types: Option<Vec<String>>,
+}
+
+impl Webhook {
+    pub(crate) fn accepts(&self, event: &WebhookEvent) -> bool {
+        match &self.event_types {
+            None => true,
+            Some(event_types) => event_types.iter().any(|name| name == event.name()),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub(crate) enum WebhookEvent {
+    ChannelOccupied {
+        channel: String,
+    },
+    ChannelVacated {
+        channel: String,
+    },
+    MemberAdded {
+        channel: String,
+        user_id: String,
+    },
+    MemberRemoved {
+        channel: String,
+        user_id: String,
+    },
+    CacheMiss {
+        channel: String,
+    },
+    ClientEvent {
+        channel: String,
+        event: String,
+        data: String,
+        socket_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        user_id: Option<String>,
+    },
+}
+
+impl From<ChannelTransition> for WebhookEvent {
+    fn from(transition: ChannelTransition) -> Self {
+        match transition {
+            ChannelTransition::Occupied(channel) => WebhookEvent::ChannelOccupied { channel },
+            ChannelTransition::Vacated(channel) => WebhookEvent::ChannelVacated { channel },
+        }
+    }
+}
+
+impl WebhookEvent {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::ChannelOccupied { .. } => "channel_occupied",
+            WebhookEvent::ChannelVacated { .. } => "channel_vacated",
+            WebhookEvent::MemberAdded { .. } => "member_added",
+            WebhookEvent::MemberRemoved { .. } => "member_removed",
+            WebhookEvent::CacheMiss { .. } => "cache_miss",
+            WebhookEvent::ClientEvent { .. } => "client_event",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    time_ms: i64,
+    events: Vec<&'a WebhookEvent>,
+}
+
+/// Queues webhook events of an app and delivers them from a background task,
+/// started on the first event so apps without webhooks never spawn one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WebhookDispatcher {
+    sender: Arc<OnceLock<mpsc::UnboundedSender<WebhookEvent>>>,
+}
+
+impl WebhookDispatcher {
+    pub(crate) fn send(&self, key: &str, secret: &str, webhooks: &[Webhook], event: WebhookEvent) {
+        let sender = self.sender.get_or_init(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(run(receiver, key.to_owned(), secret.to_owned(), webhooks.to_vec()));
+            sender
+        });
+
+        if let Err(err) = sender.send(event) {
+            eprintln!("Webhook dispatcher stopped: {}", err);
+        }
+    }
+}
+
+async fn run(mut receiver: mpsc::UnboundedReceiver<WebhookEvent>, key: String, secret: String, webhooks: Vec<Webhook>) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => return eprintln!("Cannot start webhook client: {}", err),
+    };
+    let queues = webhooks.iter().map(|webhook| {
+        let (queue, batches) = mpsc::channel(PENDING_BATCHES);
+        tokio::spawn(deliver_in_order(client.clone(), webhook.url.to_owned(), key.to_owned(), batches));
+        queue
+    }).collect::<Vec<_>>();
+
+    while let Some(event) = receiver.recv().await {
+        let mut events = vec![event];
+        let window = tokio::time::sleep(BATCH_WINDOW);
+        tokio::pin!(window);
+        loop {
+            tokio::select! {
+                _ = &mut window => break,
+                event = receiver.recv() => match event {
+                    Some(event) => events.push(event),
+                    None => break,
+                },
+            }
+        }
+
+        for (webhook, queue) in webhooks.iter().zip(&queues) {
+            let payload = WebhookPayload {
+                time_ms: chrono::Utc::now().timestamp_millis(),
+                events: events.iter().filter(|event| webhook.accepts(event)).collect(),
+            };
+            if payload.events.is_empty() {
+                continue;
+            }
+
+            let body = serde_json::to_string(&payload).unwrap();
+            let signature = create_auth_signature(&body, &secret);
+            if queue.try_send((signature, body)).is_err() {
+                eprintln!("Webhook {} is not keeping up, dropped {} events", webhook.url, payload.events.len());
+            }
+        }
+    }
+}
+
+/// Delivers the batches for one url one after the other, so that they arrive in the order
+/// their events happened even when some need retries.
+async fn deliver_in_order(client: reqwest::Client, url: String, key: String, mut batches: mpsc::Receiver<(String, String)>) {
+    while let Some((signature, body)) = batches.recv().await {
+        deliver(&client, &url, &key, &signature, body).await;
+    }
+}
+
+async fn deliver(client: &reqwest::Client, url: &str, key: &str, signature: &str, body: String) {
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let response = client.post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Pusher-Key", key)
+            .header("X-Pusher-Signature", signature)
+            .body(body.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => eprintln!("Webhook {} attempt {} failed: {}", url, attempt, response.status()),
+            Err(err) => eprintln!("Webhook {} attempt {} failed: {}", url, attempt, err),
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use serde_json::Value;
+    use tokio::sync::mpsc;
+    use warp::Filter;
+    use warp::http::{HeaderMap, StatusCode};
+    use warp::hyper::body::Bytes;
+    use crate::app::{create_auth_signature, json, MemoryAdapter, Pusher, Webhook, WebhookEvent};
+    use crate::app::testing::{node, subscribe, until};
+
+    /// Local HTTP endpoint recording every delivery, failing the first `failures` requests.
+    async fn stub(failures: usize) -> (String, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let remaining = Arc::new(AtomicUsize::new(failures));
+        let route = warp::post().and(warp::header::headers_cloned()).and(warp::body::bytes()).map(move |headers, body| {
+            sender.send((headers, body)).unwrap();
+            match remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)) {
+                Ok(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                Err(_) => StatusCode::OK,
+            }
+        });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/webhooks", address), receiver)
+    }
+
+    fn app(url: &str, event_types: Option<Vec<&str>>) -> Pusher {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        pusher.set_webhooks(vec![Webhook {
+            url: url.to_owned(),
+            event_types: event_types.map(|types| types.into_iter().map(str::to_owned).collect()),
+        }]);
+        pusher
+    }
+
+    async fn next(receiver: &mut mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) -> (HeaderMap, Value) {
+        let (headers, body) = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        (headers, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_batches() {
+        let (url, mut requests) = stub(0).await;
+        let pusher = app(&url, None);
+
+        pusher.emit_webhook(WebhookEvent::ChannelOccupied { channel: "room".to_owned() });
+        pusher.emit_webhook(WebhookEvent::MemberAdded { channel: "presence-room".to_owned(), user_id: "1".to_owned() });
+
+        let (headers, body) = next(&mut requests).await;
+        assert_eq!(body["events"], json!([
+            {"name": "channel_occupied", "channel": "room"},
+            {"name": "member_added", "channel": "presence-room", "user_id": "1"},
+        ]));
+        assert!(body["time_ms"].as_i64().unwrap() > 0);
+        assert_eq!(headers["X-Pusher-Key"], "key");
+        let signature = create_auth_signature(&serde_json::to_string(&body).unwrap(), "secret");
+        assert_eq!(headers["X-Pusher-Signature"], signature.as_str());
+    }
+
+    #[tokio::test]
+    async fn filters_events_by_type() {
+        let (url, mut requests) = stub(0).await;
+        let pusher = app(&url, Some(vec!["channel_vacated"]));
+
+        pusher.emit_webhook(WebhookEvent::ChannelOccupied { channel: "room".to_owned() });
+        pusher.emit_webhook(WebhookEvent::ChannelVacated { channel: "room".to_owned() });
+
+        let (_, body) = next(&mut requests).await;
+        assert_eq!(body["events"], json!([{"name": "channel_vacated", "channel": "room"}]));
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries() {
+        let (url, mut requests) = stub(2).await;
+        let pusher = app(&url, None);
+
+        pusher.emit_webhook(WebhookEvent::ChannelOccupied { channel: "room".to_owned() });
+
+        let (_, first) = next(&mut requests).await;
+        let (_, second) = next(&mut requests).await;
+        let (_, third) = next(&mut requests).await;
+        assert_eq!(first, second);
+        assert_eq!(second, third);
+        assert!(tokio::time::timeout(Duration::from_millis(500), requests.recv()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn later_batches_wait_for_retries_of_earlier_ones() {
+        let (url, mut requests) = stub(2).await;
+        let pusher = app(&url, None);
+
+        pusher.emit_webhook(WebhookEvent::ChannelOccupied { channel: "room".to_owned() });
+        let (_, failed) = next(&mut requests).await;
+        pusher.emit_webhook(WebhookEvent::ChannelVacated { channel: "room".to_owned() });
+
+        let occupied = json!([{"name": "channel_occupied", "channel": "room"}]);
+        assert_eq!(failed["events"], occupied);
+        assert_eq!(next(&mut requests).await.1["events"], occupied);
+        assert_eq!(next(&mut requests).await.1["events"], occupied);
+        assert_eq!(next(&mut requests).await.1["events"], json!([{"name": "channel_vacated", "channel": "room"}]));
+    }
+
+    #[tokio::test]
+    async fn websocket_lifecycle_emits_channel_events() {
+        let (url, mut requests) = stub(0).await;
+        let pusher = app(&url, None);
+        pusher.relay_channel_webhooks();
+        let handler = pusher.clone();
+        let filter = warp::query().and(warp::ws()).and_then(move |query, ws| crate::handlers::ws(handler.clone(), query, None, ws));
+        let mut client = warp::test::ws().path("/?protocol=7").handshake(filter).await.unwrap();
+        client.recv().await.unwrap();
+
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "room"}}).to_string()).await;
+        client.recv().await.unwrap();
+        let (_, body) = next(&mut requests).await;
+        assert_eq!(body["events"], json!([{"name": "channel_occupied", "channel": "room"}]));
+
+        drop(client);
+        let (_, body) = next(&mut requests).await;
+        assert_eq!(body["events"], json!([{"name": "channel_vacated", "channel": "room"}]));
+        let mut channels = 0;
+        pusher.channels.for_each(|_, _| channels += 1);
+        assert_eq!(channels, 0);
+    }
+
+    #[tokio::test]
+    async fn channels_occupied_on_several_nodes_are_reported_once() {
+        let (url, mut requests) = stub(0).await;
+        let adapter = MemoryAdapter::new();
+        let (mut first, mut second) = (node(&adapter).await, node(&adapter).await);
+        for pusher in [&mut first, &mut second] {
+            pusher.set_webhooks(vec![Webhook { url: url.to_owned(), event_types: None }]);
+            pusher.relay_channel_webhooks();
+        }
+
+        let _first = subscribe(&first, "room", "1.1", None).await;
+        let (_, body) = next(&mut requests).await;
+        assert_eq!(body["events"], json!([{"name": "channel_occupied", "channel": "room"}]));
+        until(|| second.cluster.is_occupied_elsewhere("room")).await;
+
+        let _second = subscribe(&second, "room", "2.1", None).await;
+        until(|| first.cluster.is_occupied_elsewhere("room")).await;
+        first.channels.update("room", |channel| channel.remove_subscription("1.1"));
+        until(|| !second.cluster.is_occupied_elsewhere("room")).await;
+        second.channels.update("room", |channel| channel.remove_subscription("2.1"));
+
+        let (_, body) = next(&mut requests).await;
+        assert_eq!(body["events"], json!([{"name": "channel_vacated", "channel": "room"}]));
+        assert!(tokio::time::timeout(Duration::from_millis(500), requests.recv()).await.is_err());
+    }
+}
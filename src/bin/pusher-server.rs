@@ -0,0 +1,211 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use pusher_server::{Config, ConfigError, Pusher};
+
+/// Exit code for configuration problems, following sysexits(3) `EX_CONFIG`.
+const EXIT_CONFIG: u8 = 78;
+const EXIT_FAILURE: u8 = 1;
+
+#[derive(Debug, Parser)]
+#[command(name = "pusher-server", version, about = "A Pusher protocol compatible websocket server")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve the apps of a configuration file, or a single app from flags and environment
+    Serve(ServeArgs),
+    /// Validate a configuration file without starting the server
+    CheckConfig {
+        #[arg(long, short, env = "PUSHER_CONFIG")]
+        config: PathBuf,
+    },
+    /// Print a new app with a random id, key and secret
+    GenerateApp {
+        #[arg(long, value_enum, default_value_t = Format::Toml)]
+        format: Format,
+        #[arg(long)]
+        name: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// TOML, JSON or YAML file describing the apps; the single app flags are ignored when set
+    #[arg(long, short, env = "PUSHER_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "PUSHER_APP_ID")]
+    app_id: Option<u32>,
+    #[arg(long, env = "PUSHER_APP_KEY")]
+    app_key: Option<String>,
+    #[arg(long, env = "PUSHER_APP_SECRET", hide_env_values = true)]
+    app_secret: Option<String>,
+    #[arg(long, env = "BIND_ADDRESS", default_value = "0.0.0.0:6001")]
+    bind_address: String,
+    /// Redis server relaying events between nodes, overrides `redis_url` of the config file
+    #[arg(long, env = "REDIS_URL")]
+    redis_url: Option<String>,
+}
+
+impl ServeArgs {
+    fn config(&self) -> Result<Config, ConfigError> {
+        if let Some(path) = &self.config {
+            let mut config = Config::from_file(path)?;
+            if self.redis_url.is_some() {
+                config.redis_url = self.redis_url.clone();
+                config.validate()?;
+            }
+            return Ok(config);
+        }
+
+        match (self.app_id, &self.app_key, &self.app_secret) {
+            (Some(id), Some(key), Some(secret)) => {
+                let config = Config { apps: vec![Pusher::new(id, key, secret)], redis_url: self.redis_url.clone() };
+                config.validate()?;
+                Ok(config)
+            }
+            _ => Err(ConfigError::Invalid(vec![
+                "either --config/PUSHER_CONFIG or --app-id/PUSHER_APP_ID, --app-key/PUSHER_APP_KEY \
+                 and --app-secret/PUSHER_APP_SECRET must be set".to_owned(),
+            ])),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+fn main() -> ExitCode {
+    match Cli::parse().command {
+        Command::Serve(args) => serve(args),
+        Command::CheckConfig { config } => check_config(config),
+        Command::GenerateApp { format, name } => generate_app(format, name),
+    }
+}
+
+fn serve(args: ServeArgs) -> ExitCode {
+    let config = match args.config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            eprintln!("Cannot start runtime: {}", err);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+
+    match runtime.block_on(pusher_server::start_with(config, &args.bind_address)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn check_config(path: PathBuf) -> ExitCode {
+    match Config::from_file(&path) {
+        Ok(config) => {
+            println!("{}: {} app(s) OK", path.display(), config.apps.len());
+            for app in &config.apps {
+                println!("  id {} key {}{}", app.id, app.key, app.name.as_ref().map(|name| format!(" ({})", name)).unwrap_or_default());
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(EXIT_CONFIG)
+        }
+    }
+}
+
+fn generate_app(format: Format, name: Option<String>) -> ExitCode {
+    let mut app = Pusher::new(rand::random::<u32>() % 9_000_000 + 1_000_000, &random_hex(20), &random_hex(20));
+    if let Some(name) = name {
+        app.set_name(&name);
+    }
+
+    match render(&app, format) {
+        Ok(output) => {
+            print!("{}", output);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("Cannot render app: {}", err);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+/// Renders the app as a config file containing only its credentials and name.
+fn render(app: &Pusher, format: Format) -> Result<String, pusher_server::Error> {
+    let mut entry = serde_json::json!({ "id": app.id, "key": app.key, "secret": app.secret });
+    if let Some(name) = &app.name {
+        entry["name"] = name.as_str().into();
+    }
+    let config = serde_json::json!({ "apps": [entry] });
+
+    Ok(match format {
+        Format::Toml => toml::to_string(&config)?,
+        Format::Json => format!("{}\n", serde_json::to_string_pretty(&config)?),
+        Format::Yaml => serde_yaml::to_string(&config)?,
+    })
+}
+
+fn random_hex(len: usize) -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (0..len).map(|_| std::char::from_digit(rng.gen_range(0..16), 16).unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use pusher_server::ConfigFormat;
+    use super::*;
+
+    #[test]
+    fn flags_describe_a_single_app() {
+        let cli = Cli::try_parse_from(["pusher-server", "serve", "--app-id", "1", "--app-key", "key", "--app-secret", "secret", "--bind-address", "127.0.0.1:7000", "--redis-url", "redis://redis:6379"]).unwrap();
+        let Command::Serve(args) = cli.command else { panic!("expected serve") };
+
+        assert_eq!(args.bind_address, "127.0.0.1:7000");
+        let config = args.config().unwrap();
+        assert_eq!(config.apps[0].id, 1);
+        assert_eq!(config.apps[0].key, "key");
+        assert_eq!(config.redis_url.as_deref(), Some("redis://redis:6379"));
+    }
+
+    #[test]
+    fn serve_without_apps_is_a_config_error() {
+        let args = ServeArgs { config: None, app_id: Some(1), app_key: None, app_secret: None, bind_address: String::new(), redis_url: None };
+
+        assert!(matches!(args.config(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn generated_apps_are_valid_config_files() {
+        let mut app = Pusher::new(1234567, &random_hex(20), &random_hex(20));
+        app.set_name("generated");
+
+        for (format, parse_as) in [(Format::Toml, ConfigFormat::Toml), (Format::Json, ConfigFormat::Json), (Format::Yaml, ConfigFormat::Yaml)] {
+            let config = Config::parse(&render(&app, format).unwrap(), parse_as).unwrap();
+            assert_eq!(config.apps[0].id, 1234567);
+            assert_eq!(config.apps[0].key, app.key);
+            assert_eq!(config.apps[0].name.as_deref(), Some("generated"));
+        }
+        assert!(app.key.chars().all(|c| c.is_ascii_hexdigit()) && app.key.len() == 20);
+    }
+}
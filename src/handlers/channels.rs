@@ -1,14 +1,146 @@
-use crate::app::{PusherQuery, Pusher, JsonResponse};
-use crate::handlers::{ChannelResponse, ChannelsResponse};
+use crate::app::{CustomError, PusherQuery, Pusher, JsonResponse};
+use crate::handlers::{ChannelResponse, ChannelsResponse, UsersResponse};
 
 pub(crate) async fn list_channels(pusher: Pusher, query: PusherQuery) -> JsonResponse {
-    let response = ChannelsResponse::from((pusher.get_channels().await?, &query));
+    query.ensure_valid_info(&pusher, query.filter_by_prefix.as_deref())?;
+    let response = ChannelsResponse::from((pusher.cluster.channels(&pusher.channels), &pusher.channels, &query));
 
     Ok(warp::reply::json(&response))
 }
 
 pub(crate) async fn get_channel(pusher: Pusher, query: PusherQuery, channel_name: String) -> JsonResponse {
-    let response = ChannelResponse::from((&pusher.get_channel(channel_name).await?, &query));
+    query.ensure_valid_info(&pusher, Some(&channel_name))?;
+    let membership = pusher.channels.inspect(&channel_name, |channel| pusher.cluster.channel(&channel_name, channel));
+    let response = ChannelResponse::from((channel_name.as_str(), &membership, &pusher.channels, &query));
 
     Ok(warp::reply::json(&response))
 }
+
+pub(crate) async fn get_channel_users(pusher: Pusher, _query: PusherQuery, channel_name: String) -> JsonResponse {
+    let users = pusher.channels.inspect(&channel_name, |channel| pusher.cluster.channel(&channel_name, channel)).users;
+    let users = users.ok_or_else(|| warp::reject::custom(CustomError::ChannelNotPresence))?;
+
+    Ok(warp::reply::json(&UsersResponse::from(users.into_keys().collect::<Vec<_>>())))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::{CustomError, EventRequestBody, Pusher, PusherQuery, json};
+    use crate::app::testing::{body, query, subscribe};
+    use crate::handlers::{get_channel, get_channel_users, list_channels};
+
+    fn query_with(info: &str, filter_by_prefix: Option<&str>) -> PusherQuery {
+        PusherQuery {
+            info: Some(info.to_owned().into()),
+            filter_by_prefix: filter_by_prefix.map(str::to_owned),
+            ..query()
+        }
+    }
+
+    fn rejected_with(rejection: warp::Rejection) -> String {
+        rejection.find::<CustomError>().map(ToString::to_string).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn lists_each_presence_member_once() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let _laptop = subscribe(&pusher, "presence-room", "1.1", Some("alice")).await;
+        let _phone = subscribe(&pusher, "presence-room", "2.2", Some("alice")).await;
+        let _bob = subscribe(&pusher, "presence-room", "3.3", Some("bob")).await;
+
+        let reply = get_channel_users(pusher.clone(), query(), "presence-room".to_owned()).await.unwrap();
+        let mut users = body(reply).await["users"].as_array().unwrap().clone();
+        users.sort_by_key(|user| user["id"].as_str().unwrap().to_owned());
+        assert_eq!(users, vec![json!({"id": "alice"}), json!({"id": "bob"})]);
+
+        let reply = get_channel_users(pusher, query(), "presence-empty".to_owned()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"users": []}));
+    }
+
+    #[tokio::test]
+    async fn rejects_channels_without_presence() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let _subscription = subscribe(&pusher, "private-room", "1.1", None).await;
+
+        for channel in ["private-room", "room"] {
+            let rejection = get_channel_users(pusher.clone(), query(), channel.to_owned()).await.err().unwrap();
+            assert!(matches!(rejection.find::<CustomError>(), Some(CustomError::ChannelNotPresence)));
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_occupied_channels_with_requested_info() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        let reply = list_channels(pusher.clone(), query()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"channels": {}}));
+
+        pusher.set_subscription_count_enabled(true);
+        let _room = subscribe(&pusher, "room", "1.1", None).await;
+        let _laptop = subscribe(&pusher, "presence-room", "1.1", Some("alice")).await;
+        let _phone = subscribe(&pusher, "presence-room", "2.2", Some("alice")).await;
+
+        let reply = list_channels(pusher.clone(), query()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"channels": {"room": {}, "presence-room": {}}}));
+
+        let reply = list_channels(pusher, query_with("user_count,subscription_count", Some("presence-"))).await.unwrap();
+        assert_eq!(body(reply).await, json!({"channels": {"presence-room": {"user_count": 1, "subscription_count": 2}}}));
+    }
+
+    #[tokio::test]
+    async fn unoccupied_channels_are_reported_as_such() {
+        let pusher = Pusher::new(1, "key", "secret");
+
+        let reply = get_channel(pusher.clone(), query(), "room".to_owned()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"occupied": false}));
+
+        let receiver = subscribe(&pusher, "presence-room", "1.1", Some("alice")).await;
+        let reply = get_channel(pusher.clone(), query_with("user_count", None), "presence-room".to_owned()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"occupied": true, "user_count": 1}));
+
+        drop(receiver);
+        pusher.channels.update("presence-room", |channel| channel.remove_subscription("1.1"));
+        let reply = get_channel(pusher, query_with("user_count", None), "presence-room".to_owned()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"occupied": false}));
+    }
+
+    #[tokio::test]
+    async fn rejects_info_the_channel_cannot_provide() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        let _room = subscribe(&pusher, "private-room", "1.1", None).await;
+
+        let rejection = get_channel(pusher.clone(), query_with("user_count", None), "private-room".to_owned()).await.err().unwrap();
+        assert_eq!(rejected_with(rejection), CustomError::UserCountNotPresence.to_string());
+        let rejection = list_channels(pusher.clone(), query_with("user_count", None)).await.err().unwrap();
+        assert_eq!(rejected_with(rejection), CustomError::UserCountNotPresence.to_string());
+
+        let rejection = get_channel(pusher.clone(), query_with("subscription_count", None), "private-room".to_owned()).await.err().unwrap();
+        assert_eq!(rejected_with(rejection), CustomError::SubscriptionCountDisabled.to_string());
+
+        pusher.set_subscription_count_enabled(true);
+        let reply = get_channel(pusher, query_with("subscription_count", None), "private-room".to_owned()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"occupied": true, "subscription_count": 1}));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_cache_status_of_cache_channels() {
+        let pusher = Pusher::new(1, "key", "secret");
+
+        let reply = get_channel(pusher.clone(), query_with("cache", None), "cache-room".to_owned()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"occupied": false, "cache": {"cached": false}}));
+
+        let request: EventRequestBody = serde_json::from_value(json!({"name": "score", "channel": "cache-room", "data": "1-0"})).unwrap();
+        request.payload_publish(pusher.clone()).await.unwrap();
+        let reply = get_channel(pusher.clone(), query_with("cache", None), "cache-room".to_owned()).await.unwrap();
+        let response = body(reply).await;
+        assert_eq!(response["occupied"], false);
+        assert_eq!(response["cache"]["cached"], true);
+        assert!(response["cache"]["ttl"].as_u64().unwrap() > 1790);
+
+        tokio::time::advance(std::time::Duration::from_secs(30 * 60)).await;
+        let reply = get_channel(pusher.clone(), query_with("cache", None), "cache-room".to_owned()).await.unwrap();
+        assert_eq!(body(reply).await, json!({"occupied": false, "cache": {"cached": false}}));
+
+        let rejection = get_channel(pusher, query_with("cache", None), "room".to_owned()).await.err().unwrap();
+        assert_eq!(rejected_with(rejection), CustomError::CacheNotCacheChannel.to_string());
+    }
+}
@@ -14,10 +14,16 @@ pub(crate) async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejec
     if err.is_not_found() {
         message = "Not Found".to_string();
         code = StatusCode::NOT_FOUND;
-    } else if let Some(_) = err.find::<warp::filters::body::BodyDeserializeError>() {
+    } else if err.find::<warp::filters::body::BodyDeserializeError>().is_some() {
         message = "Invalid Body".to_string();
         code = StatusCode::BAD_REQUEST;
-    } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
+    } else if err.find::<warp::reject::InvalidQuery>().is_some() {
+        message = "Invalid Query".to_string();
+        code = StatusCode::BAD_REQUEST;
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        message = "Payload Too Large".to_string();
+        code = StatusCode::PAYLOAD_TOO_LARGE;
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         message = "Method Not Allowed".to_string();
         code = StatusCode::METHOD_NOT_ALLOWED;
     } else if let Some(e) = err.find::<CustomError>() {
@@ -34,6 +40,25 @@ pub(crate) async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejec
             CustomError::AppIdNotFound => StatusCode::NOT_FOUND,
             CustomError::AuthKeyMismatch => StatusCode::UNAUTHORIZED,
             CustomError::AuthSignatureError => StatusCode::UNAUTHORIZED,
+            CustomError::InvalidChannelData => StatusCode::BAD_REQUEST,
+            CustomError::ClientEventsDisabled => StatusCode::FORBIDDEN,
+            CustomError::ClientEventInvalidName => StatusCode::BAD_REQUEST,
+            CustomError::ClientEventForbidden => StatusCode::FORBIDDEN,
+            CustomError::BatchTooLarge => StatusCode::BAD_REQUEST,
+            CustomError::AuthTimestampExpired => StatusCode::UNAUTHORIZED,
+            CustomError::BodyMd5Mismatch => StatusCode::UNAUTHORIZED,
+            CustomError::InvalidBody => StatusCode::BAD_REQUEST,
+            CustomError::InvalidUserData => StatusCode::BAD_REQUEST,
+            CustomError::UserCountNotPresence => StatusCode::BAD_REQUEST,
+            CustomError::SubscriptionCountDisabled => StatusCode::BAD_REQUEST,
+            CustomError::EncryptedDataInvalid => StatusCode::BAD_REQUEST,
+            CustomError::EncryptedChannelNotAlone => StatusCode::BAD_REQUEST,
+            CustomError::CacheNotCacheChannel => StatusCode::BAD_REQUEST,
+            CustomError::ChannelNameInvalid => StatusCode::BAD_REQUEST,
+            CustomError::ChannelNameTooLong => StatusCode::BAD_REQUEST,
+            CustomError::TooManyChannels => StatusCode::BAD_REQUEST,
+            CustomError::EventNameTooLong => StatusCode::BAD_REQUEST,
+            CustomError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
         };
     } else {
         eprintln!("unhandled error: {:?}", err);
@@ -43,7 +68,7 @@ pub(crate) async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejec
 
     let json = warp::reply::json(&ErrorMessage {
         code: code.as_u16(),
-        message: message.into(),
+        message,
     });
 
     Ok(warp::reply::with_status(json, code))
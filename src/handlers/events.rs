@@ -1,8 +1,138 @@
-use crate::app::{EventRequestBody, JsonResponse, Pusher, PusherQuery, json};
+use crate::app::{BatchEventRequestBody, EventRequestBody, JsonResponse, Pusher, PusherQuery, json};
+use crate::handlers::{BatchEventsResponse, EventInfo};
 
 pub(crate) async fn event_create(pusher: Pusher, _query: PusherQuery, request: EventRequestBody) -> JsonResponse {
     request.payload_publish(pusher).await?;
     Ok(warp::reply::json(&json!({})))
 }
 
+pub(crate) async fn batch_event_create(pusher: Pusher, _query: PusherQuery, request: BatchEventRequestBody) -> JsonResponse {
+    request.payload_publish(pusher.clone()).await?;
+    if !request.has_info() {
+        return Ok(warp::reply::json(&json!({})));
+    }
 
+    let batch = request.batch.iter().map(|event| {
+        let name = event.channel.as_deref().unwrap_or_default();
+        EventInfo::from((event, &pusher.channels.inspect(name, |channel| pusher.cluster.channel(name, channel))))
+    }).collect();
+
+    Ok(warp::reply::json(&BatchEventsResponse { batch }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use crate::app::{BatchEventRequestBody, CustomError, Pusher, json};
+    use crate::app::testing::{body, query, subscribe};
+    use crate::handlers::{batch_event_create, event_create};
+
+    fn batch(events: Value) -> BatchEventRequestBody {
+        serde_json::from_value(json!({ "batch": events })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn batch_publishes_every_event_and_skips_excluded_socket() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let first = subscribe(&pusher, "first", "1.1", None).await;
+        let second = subscribe(&pusher, "second", "2.2", None).await;
+        let request = batch(json!([
+            {"channel": "first", "name": "a", "data": "1"},
+            {"channel": "second", "name": "b", "data": "2", "socket_id": "2.2"},
+        ]));
+
+        let reply = batch_event_create(pusher, query(), request).await.unwrap();
+
+        assert_eq!(body(reply).await, json!({}));
+        let event = serde_json::from_str::<Value>(&first.try_recv().unwrap()).unwrap();
+        assert_eq!(event["event"], "a");
+        assert_eq!(event["channel"], "first");
+        assert!(second.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn batch_reports_requested_info_per_event() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let _public = subscribe(&pusher, "room", "1.1", None).await;
+        let _presence = subscribe(&pusher, "presence-room", "1.1", Some("alice")).await;
+        let _presence_again = subscribe(&pusher, "presence-room", "2.2", Some("alice")).await;
+        let request = batch(json!([
+            {"channel": "room", "name": "a", "data": "1", "info": "subscription_count"},
+            {"channel": "presence-room", "name": "b", "data": "2", "info": "subscription_count,user_count"},
+            {"channel": "presence-empty", "name": "c", "data": "3", "info": "user_count"},
+            {"channel": "room", "name": "d", "data": "4"},
+        ]));
+
+        let reply = batch_event_create(pusher, query(), request).await.unwrap();
+
+        assert_eq!(body(reply).await, json!({"batch": [
+            {"subscription_count": 1},
+            {"subscription_count": 2, "user_count": 1},
+            {"user_count": 0},
+            {},
+        ]}));
+    }
+
+    #[tokio::test]
+    async fn batch_rejects_more_than_ten_events_or_missing_channel() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let receiver = subscribe(&pusher, "room", "1.1", None).await;
+        let too_many = (0..11).map(|i| json!({"channel": "room", "name": "e", "data": i.to_string()})).collect();
+
+        let err = batch_event_create(pusher.clone(), query(), batch(Value::Array(too_many))).await.err().unwrap();
+        assert!(matches!(err.find::<CustomError>(), Some(CustomError::BatchTooLarge)));
+
+        let missing = batch(json!([{"channel": "room", "name": "e", "data": "1"}, {"channels": ["room"], "name": "e", "data": "2"}]));
+        let err = batch_event_create(pusher, query(), missing).await.err().unwrap();
+        assert!(matches!(err.find::<CustomError>(), Some(CustomError::EventChannelEmpty)));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn encrypted_channels_only_accept_encrypted_envelopes() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let receiver = subscribe(&pusher, "private-encrypted-room", "1.1", None).await;
+        let envelope = json!({"nonce": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEB", "ciphertext": "AgICAgICAgICAgICAgICAgICAgI="}).to_string();
+
+        let plaintext = serde_json::from_value(json!({"channel": "private-encrypted-room", "name": "e", "data": "{\"secret\":1}"})).unwrap();
+        let err = event_create(pusher.clone(), query(), plaintext).await.err().unwrap();
+        assert!(matches!(err.find::<CustomError>(), Some(CustomError::EncryptedDataInvalid)));
+
+        let shared = serde_json::from_value(json!({"channels": ["private-encrypted-other", "private-encrypted-room"], "name": "e", "data": envelope})).unwrap();
+        let err = event_create(pusher.clone(), query(), shared).await.err().unwrap();
+        assert!(matches!(err.find::<CustomError>(), Some(CustomError::EncryptedChannelNotAlone)));
+
+        let mixed = batch(json!([
+            {"channel": "private-encrypted-room", "name": "e", "data": envelope},
+            {"channel": "private-encrypted-room", "name": "e", "data": "plain"},
+        ]));
+        let err = batch_event_create(pusher.clone(), query(), mixed).await.err().unwrap();
+        assert!(matches!(err.find::<CustomError>(), Some(CustomError::EncryptedDataInvalid)));
+        assert!(receiver.try_recv().is_err());
+
+        let encrypted = serde_json::from_value(json!({"channel": "private-encrypted-room", "name": "e", "data": envelope})).unwrap();
+        event_create(pusher, query(), encrypted).await.unwrap();
+        let event = serde_json::from_str::<Value>(&receiver.try_recv().unwrap()).unwrap();
+        assert!(event["data"].as_str().unwrap().contains("ciphertext"));
+    }
+
+    #[tokio::test]
+    async fn events_with_invalid_names_or_oversized_data_are_rejected() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        pusher.set_max_payload_size(8);
+        let receiver = subscribe(&pusher, "room", "1.1", None).await;
+
+        let invalid = serde_json::from_value(json!({"channels": ["room", "bad room"], "name": "e", "data": "1"})).unwrap();
+        let err = event_create(pusher.clone(), query(), invalid).await.err().unwrap();
+        assert!(matches!(err.find::<CustomError>(), Some(CustomError::ChannelNameInvalid)));
+
+        let oversized = batch(json!([{"channel": "room", "name": "e", "data": "1"}, {"channel": "room", "name": "e", "data": "123456789"}]));
+        let err = batch_event_create(pusher.clone(), query(), oversized).await.err().unwrap();
+        assert!(matches!(err.find::<CustomError>(), Some(CustomError::PayloadTooLarge)));
+
+        let long_name = serde_json::from_value(json!({"channel": "room", "name": "e".repeat(201), "data": "1"})).unwrap();
+        let err = event_create(pusher, query(), long_name).await.err().unwrap();
+        assert!(matches!(err.find::<CustomError>(), Some(CustomError::EventNameTooLong)));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -3,12 +3,16 @@ mod channels;
 mod events;
 mod websocket;
 mod responses;
+mod stats;
+mod users;
 
 pub(crate) use errors::handle_rejection;
-pub(crate) use responses::{ChannelsResponse, ChannelResponse};
-pub(crate) use channels::{get_channel, list_channels};
-pub(crate) use events::event_create;
-pub(crate) use websocket::ws;
+pub(crate) use responses::{ChannelsResponse, ChannelResponse, UsersResponse, BatchEventsResponse, EventInfo, StatsResponse};
+pub(crate) use channels::{get_channel, get_channel_users, list_channels};
+pub(crate) use events::{event_create, batch_event_create};
+pub(crate) use stats::{get_stats, metrics};
+pub(crate) use users::terminate_user_connections;
+pub(crate) use websocket::{ws, ws_unknown_app};
 
 pub(crate) async fn index() -> Result<impl warp::Reply, warp::Rejection> {
     Ok(warp::http::status::StatusCode::NOT_FOUND)
@@ -1,19 +1,16 @@
-use crate::app::{Channel, PusherQuery, HashMap, Serialize};
+use crate::app::{is_cache_channel, ChannelMembership, ChannelRegistry, EventRequestBody, Pusher, PusherQuery, HashMap, Serialize};
 
 #[derive(Serialize, Clone)]
 pub(crate) struct ChannelsResponse {
     pub channels: HashMap<String, Info>,
 }
 
-impl From<(HashMap<String, Channel>, &PusherQuery)> for ChannelsResponse {
-    fn from((c, q): (HashMap<String, Channel>, &PusherQuery)) -> Self {
-        let result = if let Some(ref prefix) = q.filter_by_prefix {
-            c.iter().filter(|(channel_name, channel)| !channel.is_empty() && channel_name.starts_with(prefix.to_owned().as_str()))
-                .map(move |(channel_name, channel)| (channel_name.to_owned(), Info::from((channel, q)))).collect::<HashMap<String, Info>>()
-        } else {
-            c.iter().filter(|(_channel_name, channel)| !channel.is_empty())
-                .map(|(channel_name, channel)| (channel_name.to_owned(), Info::from((channel, q)))).collect::<HashMap<String, Info>>()
-        };
+impl From<(HashMap<String, ChannelMembership>, &ChannelRegistry, &PusherQuery)> for ChannelsResponse {
+    fn from((m, c, q): (HashMap<String, ChannelMembership>, &ChannelRegistry, &PusherQuery)) -> Self {
+        let prefix = q.filter_by_prefix.as_deref().unwrap_or_default();
+        let result = m.iter().filter(|(channel_name, channel)| !channel.is_empty() && channel_name.starts_with(prefix))
+            .map(|(channel_name, channel)| (channel_name.to_owned(), Info::from((channel_name.as_str(), channel, c, q))))
+            .collect::<HashMap<String, Info>>();
 
         Self {
             channels: result
@@ -28,27 +25,129 @@ pub(crate) struct ChannelResponse {
     pub info: Info,
 }
 
-impl From<(&Channel, &PusherQuery)> for ChannelResponse {
-    fn from((c, q): (&Channel, &PusherQuery)) -> Self {
+impl From<(&str, &ChannelMembership, &ChannelRegistry, &PusherQuery)> for ChannelResponse {
+    fn from((n, m, c, q): (&str, &ChannelMembership, &ChannelRegistry, &PusherQuery)) -> Self {
+        if m.is_empty() {
+            // A cache channel can hold an event while nobody is subscribed.
+            return Self {
+                occupied: false,
+                info: Info {
+                    cache: CacheInfo::requested(n, c, q),
+                    ..Info::default()
+                },
+            };
+        }
         Self {
-            occupied: c.subscriptions_count() > 0,
-            info: Info::from((c, q)),
+            occupied: true,
+            info: Info::from((n, m, c, q)),
         }
     }
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, Default)]
 pub(crate) struct Info {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user_count: Option<usize>,
-    pub subscription_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheInfo>,
+}
+
+impl From<(&str, &ChannelMembership, &ChannelRegistry, &PusherQuery)> for Info {
+    fn from((n, m, c, q): (&str, &ChannelMembership, &ChannelRegistry, &PusherQuery)) -> Self {
+        let info = q.info.clone().unwrap_or_default();
+        Self {
+            user_count: info.user_count.then(|| m.users_count()).flatten(),
+            subscription_count: info.subscription_count.then_some(m.subscriptions),
+            cache: CacheInfo::requested(n, c, q),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct CacheInfo {
+    pub cached: bool,
+    /// Seconds the cached event is still replayed to new subscribers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+impl CacheInfo {
+    fn requested(n: &str, c: &ChannelRegistry, q: &PusherQuery) -> Option<Self> {
+        if !q.info.as_ref().is_some_and(|info| info.cache) || !is_cache_channel(n) {
+            return None;
+        }
+        let ttl = c.cache_ttl(n);
+        Some(Self {
+            cached: ttl.is_some(),
+            ttl: ttl.map(|ttl| ttl.as_secs()),
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct UsersResponse {
+    pub users: Vec<UserInfo>,
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct UserInfo {
+    pub id: String,
+}
+
+impl From<Vec<String>> for UsersResponse {
+    fn from(ids: Vec<String>) -> Self {
+        Self {
+            users: ids.into_iter().map(|id| UserInfo { id }).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct BatchEventsResponse {
+    pub batch: Vec<EventInfo>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub(crate) struct EventInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscription_count: Option<usize>,
+}
+
+impl From<(&EventRequestBody, &ChannelMembership)> for EventInfo {
+    fn from((e, m): (&EventRequestBody, &ChannelMembership)) -> Self {
+        let mut info = Self::default();
+        for attribute in e.info_attributes() {
+            match attribute {
+                "user_count" => info.user_count = m.users_count(),
+                "subscription_count" => info.subscription_count = Some(m.subscriptions),
+                _ => {}
+            }
+        }
+        info
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub(crate) struct StatsResponse {
+    pub connections: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<u32>,
+    pub channels: usize,
+    pub subscriptions: usize,
 }
 
-impl From<(&Channel, &PusherQuery)> for Info {
-    fn from((c, q): (&Channel, &PusherQuery)) -> Self {
+impl From<(&Pusher, &ChannelRegistry)> for StatsResponse {
+    fn from((p, c): (&Pusher, &ChannelRegistry)) -> Self {
+        let occupied = p.cluster.channels(c);
         Self {
-            user_count: if q.is_channel_presence() { c.users_count() } else { None },
-            subscription_count: c.subscriptions_count(),
+            connections: p.cluster.connections(p.connections_count()),
+            capacity: p.capacity,
+            channels: occupied.len(),
+            subscriptions: occupied.values().map(|channel| channel.subscriptions).sum(),
         }
     }
 }
@@ -0,0 +1,14 @@
+use crate::app::{render_metrics, Pusher, PusherQuery, PusherServer, JsonResponse, METRICS_CONTENT_TYPE};
+use crate::handlers::StatsResponse;
+
+pub(crate) async fn get_stats(pusher: Pusher, _query: PusherQuery) -> JsonResponse {
+    let response = StatsResponse::from((&pusher, &pusher.channels));
+
+    Ok(warp::reply::json(&response))
+}
+
+pub(crate) async fn metrics(server: PusherServer) -> Result<impl warp::Reply, warp::Rejection> {
+    let output = render_metrics(server.apps()).await;
+
+    Ok(warp::reply::with_header(output, "content-type", METRICS_CONTENT_TYPE))
+}
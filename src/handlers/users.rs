@@ -0,0 +1,7 @@
+use crate::app::{ClusterMessage, JsonResponse, Pusher, PusherQuery, json};
+
+pub(crate) async fn terminate_user_connections(pusher: Pusher, _query: PusherQuery, user_id: String) -> JsonResponse {
+    pusher.terminate_user_connections(&user_id).await;
+    pusher.cluster.broadcast(ClusterMessage::TerminateUser { user_id });
+    Ok(warp::reply::json(&json!({})))
+}
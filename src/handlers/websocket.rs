@@ -1,144 +1,992 @@
-use futures::{SinkExt, StreamExt};
-use tokio::sync::mpsc;
-use warp::filters::ws::Ws;
-use crate::app::{Pusher, generate_socket_id, ServerEvent, ConnectionInfo, Subscription, CustomEvent, Result};
-use crate::app::ClientEvent::{ChannelEvent, Subscribe, Unsubscribe, Ping};
-
-pub(crate) async fn ws(pusher: Pusher, ws: Ws) -> Result<impl warp::Reply> {
-    Ok(ws.on_upgrade(|w| async move {
+use std::sync::Arc;
+use std::time::Duration;
+use futures::{Sink, SinkExt, StreamExt};
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use warp::filters::ws::{Message, Ws};
+use crate::app::{Pusher, ConnectionQuery, HashSet, Channel, ClusterMessage, PresenceUser, generate_socket_id, ServerEvent, ConnectionInfo, Subscription, CustomEvent, CustomError, ProtocolError, SigninInfo, UserSocket, WebhookEvent, Result, DEFAULT_ACTIVITY_TIMEOUT, DEFAULT_MAX_PAYLOAD_SIZE, OUTBOX_CAPACITY, outbox, validate_channel_name, validate_event_name, validate_payload_size};
+use crate::app::ClientEvent::{ChannelEvent, Subscribe, Unsubscribe, Signin, Ping, Pong};
+
+/// How long a client may take to answer the server's `pusher:ping` before it is disconnected.
+const PONG_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) async fn ws(pusher: Pusher, query: ConnectionQuery, origin: Option<String>, ws: Ws) -> Result<impl warp::Reply> {
+    Ok(ws.on_upgrade(|mut w| async move {
+        let protocol = match query.protocol() {
+            Ok(protocol) => protocol,
+            Err(err) => {
+                close_with_error(&mut w, err).await;
+                return;
+            }
+        };
+
+        if !pusher.is_origin_allowed(origin.as_deref()) {
+            close_with_error(&mut w, ProtocolError::OriginNotAllowed(origin.unwrap_or_default())).await;
+            return;
+        }
+
+        let _connection = match pusher.try_connect() {
+            Some(connection) => connection,
+            None => {
+                close_with_error(&mut w, ProtocolError::OverConnectionQuota).await;
+                return;
+            }
+        };
+
         let (mut tx, mut rx) = w.split();
-        let (response_tx, mut response_rx) = mpsc::channel::<ServerEvent>(1024);
+        let (response_tx, response_rx) = outbox(OUTBOX_CAPACITY, pusher.slow_consumer_policy.unwrap_or_default());
 
         let socket_id = generate_socket_id();
+        let client = query.client_description();
+        eprintln!("client {} connected with {}, protocol {}", socket_id, client, protocol);
+        let activity_timeout = pusher.activity_timeout.unwrap_or(DEFAULT_ACTIVITY_TIMEOUT);
+        let activity = Notify::new();
+        let terminate = Arc::new(Notify::new());
+        let mut signed_in_as: Option<String> = None;
+        // The channels of this socket, so that leaving them does not scan every channel of the app.
+        let mut subscribed: HashSet<String> = HashSet::new();
 
         let response_stream = async {
-            while let Some(event) = response_rx.recv().await {
-                let msg = serde_json::to_string(&event).unwrap();
-                if let Err(err) = tx.send(warp::filters::ws::Message::text(msg)).await {
+            while let Some(frame) = response_rx.recv().await {
+                let bytes = frame.len();
+                if let Err(err) = tx.send(frame.into()).await {
                     eprintln!("Send error: {}", err);
                     break;
                 }
+                pusher.metrics.message_sent(bytes);
             }
         };
 
         let connection_established = ServerEvent::ConnectionEstablished {
             data: ConnectionInfo {
                 socket_id: socket_id.clone(),
-                activity_timeout: 120,
+                activity_timeout,
             },
         };
 
-        if let Err(err) = response_tx.send(connection_established).await {
+        if let Err(err) = response_tx.send(connection_established.into()).await {
             eprintln!("Failed handshake: {}", err);
-            return ();
+            return;
         }
 
         let reader = async {
+            let mut closed_by = None;
             while let Some(payload) = rx.next().await {
-                let msg = match payload.ok().and_then(|msg| msg.to_str().map(|s| s.to_owned()).ok()) {
-                    Some(msg) => msg,
-                    None => {
-                        eprintln!("invalid websocket payload");
+                let msg = match payload {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        eprintln!("Receive error: {}", err);
                         break;
                     }
                 };
 
+                // Any frame proves the client is alive, websocket level pings included.
+                activity.notify_one();
+                if msg.is_close() {
+                    break;
+                }
+                if msg.is_ping() || msg.is_pong() {
+                    continue;
+                }
+                pusher.metrics.message_received(msg.as_bytes().len());
+
+                let msg = match msg.to_str() {
+                    Ok(msg) => msg.to_owned(),
+                    Err(_) => {
+                        let error = ProtocolError::InvalidMessage("only text frames are supported".to_owned());
+                        if let Err(err) = response_tx.send(error.into()).await {
+                            eprintln!("Failed error: {}", err);
+                        }
+                        continue;
+                    }
+                };
+
                 match serde_json::from_str(&msg) {
                     Ok(Subscribe {
                         ref channel,
+                        auth,
                         channel_data,
-                        ..
                     }) => {
-                        async {
-                            let mut channels = pusher.channels.write().await;
-                            let channel =
-                                channels.entry(channel.to_owned()).or_insert(channel.to_owned().into());
-                            channel.add_subscription(
-                                &socket_id,
-                                Subscription {
-                                    sender: response_tx.clone(),
-                                    data: channel_data,
-                                    user_id: None,
-                                },
-                            );
-                        }
-                            .await;
+                        let subscription = Subscription {
+                            sender: response_tx.clone(),
+                            data: channel_data,
+                            user_id: None,
+                        };
+
+                        let subscription = match authorize_subscription(&pusher, &socket_id, channel, auth.as_deref(), subscription) {
+                            Ok(subscription) => subscription,
+                            Err(err) => {
+                                let error = ServerEvent::SubscriptionError {
+                                    channel: channel.to_owned(),
+                                    data: err.into(),
+                                };
+
+                                if let Err(err) = response_tx.send(error.into()).await {
+                                    eprintln!("Failed subscribe: {}", err);
+                                }
+                                continue;
+                            }
+                        };
+
+                        let (joined, presence_data) = pusher.channels.entry(channel, |entry| {
+                            let joined = entry.add_subscription(&socket_id, subscription).map(|member| {
+                                // Members already connected to another node were announced there.
+                                let member_added = (!pusher.cluster.has_member_elsewhere(channel, &member.id)).then(|| {
+                                    let member_added = ServerEvent::MemberAdded {
+                                        channel: channel.to_owned(),
+                                        data: member.clone(),
+                                    };
+                                    entry.delivery(&member_added, Some(&socket_id))
+                                });
+                                (member_added, member)
+                            });
+                            let presence_data = pusher.cluster.presence_data(channel, entry);
+                            (joined, presence_data)
+                        });
+                        subscribed.insert(channel.to_owned());
 
                         let success = ServerEvent::SubscriptionSucceeded {
                             channel: channel.to_owned(),
-                            data: None,
+                            data: presence_data,
                         };
 
-                        if let Err(err) = response_tx.send(success).await {
+                        if let Err(err) = response_tx.send(success.into()).await {
                             eprintln!("Failed subscribe: {}", err);
                         }
+
+                        if let Some(last_event) = pusher.channels.cached_event(channel) {
+                            let replay = match last_event {
+                                Some(event) => ServerEvent::ChannelEvent(event),
+                                None => {
+                                    pusher.emit_webhook(WebhookEvent::CacheMiss { channel: channel.to_owned() });
+                                    ServerEvent::CacheMiss { channel: channel.to_owned() }
+                                }
+                            };
+                            if let Err(err) = response_tx.send(replay.into()).await {
+                                eprintln!("Failed subscribe: {}", err);
+                            }
+                        }
+
+                        if let Some((member_added, member)) = joined {
+                            if let Some(member_added) = member_added {
+                                pusher.emit_webhook(WebhookEvent::MemberAdded {
+                                    channel: channel.to_owned(),
+                                    user_id: member.id.to_owned(),
+                                });
+                                pusher.metrics.messages_dropped(member_added.send());
+                            }
+                            pusher.cluster.broadcast(ClusterMessage::MemberAdded {
+                                channel: channel.to_owned(),
+                                member,
+                            });
+                        }
                     }
 
                     Ok(Unsubscribe { ref channel }) => {
-                        let mut channels = pusher.channels.write().await;
-                        if let Some(channel) = channels.get_mut(channel) {
-                            channel.remove_subscription(&socket_id);
-                        } else {
-                            response_tx
-                                .send(ServerEvent::Error {
-                                    message: format!(
-                                        "No current subscription to channel {}, or subscription in progress",
-                                        channel
-                                    ),
-                                    code: None,
-                                })
-                                .await
-                                .unwrap();
+                        if subscribed.remove(channel) {
+                            leave_channel(&pusher, channel, &socket_id);
+                        } else if let Err(err) = response_tx.send(ProtocolError::NotSubscribed(channel.to_owned()).into()).await {
+                            eprintln!("Failed unsubscribe: {}", err);
+                        }
+                    }
+
+                    Ok(Signin { auth, user_data }) => {
+                        let user = match pusher.ensure_valid_signin(&socket_id, auth.as_deref(), &user_data) {
+                            Ok(user) => user,
+                            Err(err) => {
+                                closed_by = Some(ProtocolError::SigninFailed(err));
+                                break;
+                            }
+                        };
+
+                        if let Some(previous) = signed_in_as.replace(user.id.to_owned()) {
+                            pusher.remove_user_socket(&previous, &socket_id).await;
+                        }
+                        let socket = UserSocket {
+                            sender: response_tx.clone(),
+                            terminate: terminate.clone(),
+                        };
+                        pusher.add_user_socket(&user.id, &socket_id, socket).await;
+
+                        let success = ServerEvent::SigninSuccess {
+                            data: SigninInfo { user_data: user.user_data },
+                        };
+                        if let Err(err) = response_tx.send(success.into()).await {
+                            eprintln!("Failed signin: {}", err);
                         }
                     }
 
                     Ok(Ping) => {
-                        response_tx.send(ServerEvent::Pong).await.unwrap();
+                        if let Err(err) = response_tx.send(ServerEvent::Pong.into()).await {
+                            eprintln!("Failed pong: {}", err);
+                        }
                     }
 
+                    Ok(Pong) => {}
+
                     Ok(ChannelEvent {
                         event,
                         ref channel,
                         data,
                     }) => {
-                        let event = ServerEvent::ChannelEvent(CustomEvent {
-                            event,
-                            channel: channel.to_owned(),
-                            data,
-                            user_id: None,
+                        let payload = match &data {
+                            serde_json::Value::String(data) => data.to_owned(),
+                            data => data.to_string(),
+                        };
+                        let authorized = pusher.channels.inspect(channel, |entry| {
+                            authorize_client_event(&pusher, entry, &socket_id, &event, &payload).map(|(entry, user_id)| {
+                                let event = CustomEvent {
+                                    event: event.to_owned(),
+                                    channel: channel.to_owned(),
+                                    data,
+                                    user_id,
+                                };
+                                (entry.delivery(&ServerEvent::ChannelEvent(event.clone()), Some(&socket_id)), event)
+                            })
                         });
-
-                        let channels = pusher.channels.read().await;
-                        if let Some(channel) = channels.get(channel) {
-                            channel.publish(event).await.unwrap();
-                        } else {
-                            eprintln!("Channel not found: {}", channel);
+                        match authorized {
+                            Ok((delivery, event)) => {
+                                pusher.channels.remember(&event);
+                                pusher.emit_webhook(WebhookEvent::ClientEvent {
+                                    channel: channel.to_owned(),
+                                    event: event.event.to_owned(),
+                                    data: payload,
+                                    socket_id: socket_id.to_owned(),
+                                    user_id: event.user_id.to_owned(),
+                                });
+                                pusher.cluster.broadcast(ClusterMessage::ChannelEvent {
+                                    event,
+                                    except: Some(socket_id.to_owned()),
+                                });
+                                pusher.metrics.messages_dropped(delivery.send());
+                            }
+                            Err(err) => {
+                                let error = ProtocolError::ClientEventRejected(err);
+                                if let Err(err) = response_tx.send(error.into()).await {
+                                    eprintln!("Failed client event: {}", err);
+                                }
+                            }
                         }
                     }
 
                     Err(err) => {
                         eprintln!("Invalid message: {}", err);
+                        let error = ProtocolError::InvalidMessage("expected a JSON encoded Pusher event".to_owned());
+                        if let Err(err) = response_tx.send(error.into()).await {
+                            eprintln!("Failed error: {}", err);
+                        }
                         continue;
                     }
                 }
             }
+            closed_by
         };
 
-        tokio::select! {
+        let keepalive = async {
+            loop {
+                if timeout(Duration::from_secs(activity_timeout.into()), activity.notified()).await.is_ok() {
+                    continue;
+                }
+                if response_tx.send(ServerEvent::Ping.into()).await.is_err() {
+                    break;
+                }
+                if timeout(PONG_TIMEOUT, activity.notified()).await.is_err() {
+                    break;
+                }
+            }
+        };
+
+        let closed_by = tokio::select! {
                     _ = response_stream => {
                         eprintln!("Response finished");
+                        None
                     },
-                    _ = reader => {
+                    closed_by = reader => {
                         eprintln!("Reader finished");
+                        closed_by
+                    },
+                    _ = keepalive => {
+                        eprintln!("Pong reply not received");
+                        Some(ProtocolError::PongNotReceived)
+                    },
+                    _ = terminate.notified() => {
+                        eprintln!("Connection terminated");
+                        Some(ProtocolError::ConnectionTerminated)
+                    },
+                    _ = response_rx.overflowed() => {
+                        eprintln!("Client not keeping up");
+                        Some(ProtocolError::OverCapacity)
                     },
                 }
         ;
 
-        let mut channels = pusher.channels.write().await;
-        for (_, channel) in channels.iter_mut() {
-            channel.remove_subscription(&socket_id);
+        if let Some(error) = closed_by {
+            close_with_error(&mut tx, error).await;
+        }
+
+        if let Some(user_id) = &signed_in_as {
+            pusher.remove_user_socket(user_id, &socket_id).await;
         }
 
-        eprintln!("client disconnected");
+        for channel in &subscribed {
+            leave_channel(&pusher, channel, &socket_id);
+        }
+
+        eprintln!("client {} ({}) disconnected, {} messages dropped", socket_id, client, response_rx.dropped());
+    }))
+}
+
+/// Answers a websocket for an app key that does not exist, so the client learns not to retry.
+pub(crate) async fn ws_unknown_app(ws: Ws) -> Result<impl warp::Reply> {
+    Ok(ws.on_upgrade(|mut w| async move {
+        close_with_error(&mut w, ProtocolError::AppNotFound).await;
     }))
 }
+
+/// Reports a fatal error as `pusher:error` and closes the socket with the same code.
+async fn close_with_error<S: Sink<Message, Error = warp::Error> + Unpin>(sink: &mut S, error: ProtocolError) {
+    debug_assert!(error.closes_connection(), "{} does not close the connection", error.code());
+    let close = Message::close_with(error.code(), error.to_string());
+    if let Err(err) = sink.send(ServerEvent::from(error).into()).await {
+        eprintln!("Send error: {}", err);
+    }
+    let _ = sink.send(close).await;
+}
+
+/// Removes the socket from the channel, notifying the remaining members and the
+/// webhooks when a presence member leaves the last node they were connected to. The
+/// registry reports the channel becoming vacant.
+fn leave_channel(pusher: &Pusher, name: &str, socket_id: &str) {
+    let removed = pusher.channels.update(name, |channel| {
+        channel.remove_subscription(socket_id).map(|member| {
+            let user_id = member.id.to_owned();
+            let member_removed = (!pusher.cluster.has_member_elsewhere(name, &user_id)).then(|| {
+                let member_removed = ServerEvent::MemberRemoved {
+                    channel: name.to_owned(),
+                    data: member,
+                };
+                channel.delivery(&member_removed, None)
+            });
+            (member_removed, user_id)
+        })
+    });
+
+    if let Some((member_removed, user_id)) = removed.flatten() {
+        if let Some(member_removed) = member_removed {
+            pusher.emit_webhook(WebhookEvent::MemberRemoved {
+                channel: name.to_owned(),
+                user_id: user_id.to_owned(),
+            });
+            pusher.metrics.messages_dropped(member_removed.send());
+        }
+        pusher.cluster.broadcast(ClusterMessage::MemberRemoved {
+            channel: name.to_owned(),
+            user_id,
+        });
+    }
+}
+
+/// Checks the channel authorization of a subscription and, for presence channels,
+/// attaches the member parsed from its `channel_data`.
+fn authorize_subscription(pusher: &Pusher, socket_id: &str, channel: &str, auth: Option<&str>, mut subscription: Subscription) -> std::result::Result<Subscription, CustomError> {
+    validate_channel_name(channel)?;
+    let kind = Channel::from(channel.to_owned());
+    if kind.requires_authentication() {
+        pusher.ensure_valid_channel_auth(socket_id, channel, auth, subscription.data.as_ref())?;
+    }
+
+    if let Channel::Presence { .. } = kind {
+        let member = PresenceUser::from_channel_data(subscription.data.as_ref())?;
+        subscription.user_id = Some(member.id);
+        subscription.data = Some(member.info);
+    }
+
+    Ok(subscription)
+}
+
+/// Applies the client event rules: the app must allow client messages, the event must be
+/// prefixed with `client-`, its name and data must be within limits and the socket must be
+/// subscribed to a private or presence channel.
+/// Returns the channel together with the sender's presence user id, if any.
+fn authorize_client_event<'a>(pusher: &Pusher, channel: Option<&'a Channel>, socket_id: &str, event: &str, data: &str) -> std::result::Result<(&'a Channel, Option<String>), CustomError> {
+    if !pusher.client_messages_enabled.unwrap_or(false) {
+        return Err(CustomError::ClientEventsDisabled);
+    }
+    if !event.starts_with("client-") {
+        return Err(CustomError::ClientEventInvalidName);
+    }
+    validate_event_name(event)?;
+    validate_payload_size(data, pusher.max_payload_size.unwrap_or(DEFAULT_MAX_PAYLOAD_SIZE))?;
+
+    let channel = channel.filter(|channel| channel.accepts_client_events()).ok_or(CustomError::ClientEventForbidden)?;
+    let subscription = channel.subscriptions().get(socket_id).ok_or(CustomError::ClientEventForbidden)?;
+
+    Ok((channel, subscription.user_id.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use warp::Filter;
+    use warp::test::WsClient;
+    use crate::app::{create_auth_signature, json, render_metrics, EventRequestBody, MemoryAdapter, Pusher};
+    use crate::app::testing::{node, until};
+    use crate::handlers::ws;
+
+    const CONNECT_PATH: &str = "/?protocol=7&client=js&version=8.4.0&flash=false";
+
+    async fn connect(pusher: &Pusher) -> (WsClient, String) {
+        let pusher = pusher.clone();
+        let filter = warp::query().and(warp::ws()).and_then(move |query, w| ws(pusher.clone(), query, None, w));
+        let mut client = warp::test::ws().path(CONNECT_PATH).handshake(filter).await.expect("handshake");
+        let established = recv_json(&mut client).await;
+        assert_eq!(established["event"], "pusher:connection_established");
+        let data: Value = serde_json::from_str(established["data"].as_str().unwrap()).unwrap();
+        (client, data["socket_id"].as_str().unwrap().to_owned())
+    }
+
+    async fn recv_json(client: &mut WsClient) -> Value {
+        let msg = client.recv().await.expect("message");
+        serde_json::from_str(msg.to_str().unwrap()).unwrap()
+    }
+
+    fn sign(key: &str, secret: &str, to_sign: &str) -> String {
+        format!("{}:{}", key, create_auth_signature(to_sign, secret))
+    }
+
+    async fn assert_silent(client: &mut WsClient) {
+        let next = tokio::time::timeout(std::time::Duration::from_millis(50), client.recv()).await;
+        assert!(next.is_err(), "unexpected message: {:?}", next);
+    }
+
+    async fn signin(client: &mut WsClient, socket_id: &str, user_id: &str) -> Value {
+        let user_data = json!({"id": user_id, "user_info": {"name": user_id}}).to_string();
+        let auth = sign("key", "secret", &format!("{}::user::{}", socket_id, user_data));
+        client.send_text(json!({"event": "pusher:signin", "data": {"auth": auth, "user_data": user_data}}).to_string()).await;
+        recv_json(client).await
+    }
+
+    async fn join_presence(client: &mut WsClient, socket_id: &str, channel: &str, user_id: &str) -> Value {
+        let channel_data = json!({"user_id": user_id, "user_info": {"name": user_id}}).to_string();
+        let auth = sign("key", "secret", &format!("{}:{}:{}", socket_id, channel, channel_data));
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": channel, "auth": auth, "channel_data": channel_data}}).to_string()).await;
+
+        let reply = recv_json(client).await;
+        assert_eq!(reply["event"], "pusher_internal:subscription_succeeded");
+        serde_json::from_str(reply["data"].as_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn private_subscription_with_valid_auth_succeeds() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut client, socket_id) = connect(&pusher).await;
+        let auth = sign("key", "secret", &format!("{}:private-room", socket_id));
+
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "private-room", "auth": auth}}).to_string()).await;
+
+        let reply = recv_json(&mut client).await;
+        assert_eq!(reply["event"], "pusher_internal:subscription_succeeded");
+        assert_eq!(reply["channel"], "private-room");
+    }
+
+    #[tokio::test]
+    async fn presence_subscription_signs_channel_data() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut client, socket_id) = connect(&pusher).await;
+        let channel_data = json!({"user_id": "1"}).to_string();
+        let auth = sign("key", "secret", &format!("{}:presence-room:{}", socket_id, channel_data));
+
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "presence-room", "auth": auth, "channel_data": channel_data}}).to_string()).await;
+
+        let reply = recv_json(&mut client).await;
+        assert_eq!(reply["event"], "pusher_internal:subscription_succeeded");
+    }
+
+    #[tokio::test]
+    async fn forged_or_missing_auth_is_rejected() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut client, socket_id) = connect(&pusher).await;
+        let forged = sign("key", "not-the-secret", &format!("{}:private-room", socket_id));
+
+        for subscribe in [
+            json!({"event": "pusher:subscribe", "data": {"channel": "private-room", "auth": forged}}),
+            json!({"event": "pusher:subscribe", "data": {"channel": "private-room"}}),
+            json!({"event": "pusher:subscribe", "data": {"channel": "presence-room", "auth": "key:zz"}}),
+        ] {
+            client.send_text(subscribe.to_string()).await;
+
+            let reply = recv_json(&mut client).await;
+            assert_eq!(reply["event"], "pusher:subscription_error");
+            let data: Value = serde_json::from_str(reply["data"].as_str().unwrap()).unwrap();
+            assert_eq!(data["status"], 401);
+        }
+        let mut channels = 0;
+        pusher.channels.for_each(|_, _| channels += 1);
+        assert_eq!(channels, 0);
+
+        let metrics = render_metrics([&pusher].into_iter()).await;
+        assert!(metrics.contains("pusher_websocket_messages_received_total{app_id=\"1\"} 3"), "{}", metrics);
+        assert!(metrics.contains("pusher_auth_failures_total{app_id=\"1\",source=\"websocket\"} 3"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn signature_from_another_app_is_rejected() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut client, socket_id) = connect(&pusher).await;
+        let auth = sign("other-key", "other-secret", &format!("{}:private-room", socket_id));
+
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "private-room", "auth": auth}}).to_string()).await;
+
+        let reply = recv_json(&mut client).await;
+        assert_eq!(reply["event"], "pusher:subscription_error");
+        assert_eq!(reply["channel"], "private-room");
+    }
+
+    #[tokio::test]
+    async fn subscription_to_invalid_channel_name_is_rejected() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut client, _) = connect(&pusher).await;
+
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "bad room"}}).to_string()).await;
+
+        let reply = recv_json(&mut client).await;
+        assert_eq!(reply["event"], "pusher:subscription_error");
+        let data: Value = serde_json::from_str(reply["data"].as_str().unwrap()).unwrap();
+        assert_eq!(data["status"], 400);
+    }
+
+    #[tokio::test]
+    async fn public_subscription_needs_no_auth() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut client, _) = connect(&pusher).await;
+
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "room"}}).to_string()).await;
+
+        let reply = recv_json(&mut client).await;
+        assert_eq!(reply["event"], "pusher_internal:subscription_succeeded");
+    }
+
+    #[tokio::test]
+    async fn presence_subscription_reports_members() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut alice, alice_socket) = connect(&pusher).await;
+        let (mut bob, bob_socket) = connect(&pusher).await;
+
+        let data = join_presence(&mut alice, &alice_socket, "presence-room", "alice").await;
+        assert_eq!(data["presence"]["count"], 1);
+        assert_eq!(data["presence"]["ids"], json!(["alice"]));
+
+        let data = join_presence(&mut bob, &bob_socket, "presence-room", "bob").await;
+        assert_eq!(data["presence"]["count"], 2);
+        assert_eq!(data["presence"]["hash"]["alice"], json!({"name": "alice"}));
+
+        let added = recv_json(&mut alice).await;
+        assert_eq!(added["event"], "pusher_internal:member_added");
+        let member: Value = serde_json::from_str(added["data"].as_str().unwrap()).unwrap();
+        assert_eq!(member, json!({"user_id": "bob", "user_info": {"name": "bob"}}));
+        assert_silent(&mut bob).await;
+    }
+
+    #[tokio::test]
+    async fn presence_members_are_reference_counted_across_sockets() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut watcher, watcher_socket) = connect(&pusher).await;
+        let (mut first, first_socket) = connect(&pusher).await;
+        let (mut second, second_socket) = connect(&pusher).await;
+
+        join_presence(&mut watcher, &watcher_socket, "presence-room", "watcher").await;
+        join_presence(&mut first, &first_socket, "presence-room", "alice").await;
+        assert_eq!(recv_json(&mut watcher).await["event"], "pusher_internal:member_added");
+
+        let data = join_presence(&mut second, &second_socket, "presence-room", "alice").await;
+        assert_eq!(data["presence"]["count"], 2);
+        assert_silent(&mut watcher).await;
+
+        first.send_text(json!({"event": "pusher:unsubscribe", "data": {"channel": "presence-room"}}).to_string()).await;
+        assert_silent(&mut watcher).await;
+
+        drop(second);
+        let removed = recv_json(&mut watcher).await;
+        assert_eq!(removed["event"], "pusher_internal:member_removed");
+        assert_eq!(removed["data"], json!({"user_id": "alice"}).to_string());
+        assert_eq!(pusher.channels.inspect("presence-room", |channel| channel.unwrap().users_count()), Some(1));
+    }
+
+    async fn expect_member(client: &mut WsClient, event: &str, user_id: &str) {
+        let received = recv_json(client).await;
+        assert_eq!(received["event"], format!("pusher_internal:{}", event));
+        let member: Value = serde_json::from_str(received["data"].as_str().unwrap()).unwrap();
+        assert_eq!(member["user_id"], user_id);
+    }
+
+    #[tokio::test]
+    async fn presence_members_on_several_nodes_are_announced_once() {
+        let adapter = MemoryAdapter::new();
+        let (first, second) = (node(&adapter).await, node(&adapter).await);
+        let (mut carol, carol_socket) = connect(&first).await;
+        join_presence(&mut carol, &carol_socket, "presence-room", "carol").await;
+        until(|| second.cluster.has_member_elsewhere("presence-room", "carol")).await;
+        let (mut bob, bob_socket) = connect(&second).await;
+        join_presence(&mut bob, &bob_socket, "presence-room", "bob").await;
+        expect_member(&mut carol, "member_added", "bob").await;
+
+        let (mut alice, alice_socket) = connect(&first).await;
+        join_presence(&mut alice, &alice_socket, "presence-room", "alice").await;
+        expect_member(&mut carol, "member_added", "alice").await;
+        expect_member(&mut bob, "member_added", "alice").await;
+
+        // Dave joining after alice connected to the second node is the next thing anyone hears.
+        let (mut other_alice, other_alice_socket) = connect(&second).await;
+        join_presence(&mut other_alice, &other_alice_socket, "presence-room", "alice").await;
+        let (mut dave, dave_socket) = connect(&second).await;
+        join_presence(&mut dave, &dave_socket, "presence-room", "dave").await;
+        expect_member(&mut bob, "member_added", "dave").await;
+        expect_member(&mut carol, "member_added", "dave").await;
+
+        drop(alice);
+        until(|| !second.cluster.has_member_elsewhere("presence-room", "alice")).await;
+        drop(dave);
+        expect_member(&mut bob, "member_removed", "dave").await;
+        expect_member(&mut carol, "member_removed", "dave").await;
+
+        drop(other_alice);
+        expect_member(&mut bob, "member_removed", "alice").await;
+        expect_member(&mut carol, "member_removed", "alice").await;
+    }
+
+    #[tokio::test]
+    async fn presence_subscription_requires_user_id() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut client, socket_id) = connect(&pusher).await;
+        let channel_data = json!({"user_info": {}}).to_string();
+        let auth = sign("key", "secret", &format!("{}:presence-room:{}", socket_id, channel_data));
+
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "presence-room", "auth": auth, "channel_data": channel_data}}).to_string()).await;
+
+        let reply = recv_json(&mut client).await;
+        assert_eq!(reply["event"], "pusher:subscription_error");
+        let data: Value = serde_json::from_str(reply["data"].as_str().unwrap()).unwrap();
+        assert_eq!(data["status"], 400);
+    }
+
+    async fn join(client: &mut WsClient, socket_id: &str, channel: &str) {
+        let auth = sign("key", "secret", &format!("{}:{}", socket_id, channel));
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": channel, "auth": auth}}).to_string()).await;
+        assert_eq!(recv_json(client).await["event"], "pusher_internal:subscription_succeeded");
+    }
+
+    fn client_messages_app() -> Pusher {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        pusher.set_client_messages_enabled(true);
+        pusher
+    }
+
+    #[tokio::test]
+    async fn client_events_reach_other_subscribers_only() {
+        let pusher = client_messages_app();
+        let (mut sender, sender_socket) = connect(&pusher).await;
+        let (mut receiver, receiver_socket) = connect(&pusher).await;
+        join(&mut sender, &sender_socket, "private-room").await;
+        join(&mut receiver, &receiver_socket, "private-room").await;
+
+        sender.send_text(json!({"event": "client-typing", "channel": "private-room", "data": {"typing": true}}).to_string()).await;
+
+        let event = recv_json(&mut receiver).await;
+        assert_eq!(event["event"], "client-typing");
+        assert_eq!(event["channel"], "private-room");
+        assert!(event.get("user_id").is_none());
+        assert_silent(&mut sender).await;
+    }
+
+    #[tokio::test]
+    async fn client_events_on_presence_carry_user_id() {
+        let pusher = client_messages_app();
+        let (mut sender, sender_socket) = connect(&pusher).await;
+        let (mut receiver, receiver_socket) = connect(&pusher).await;
+        join_presence(&mut receiver, &receiver_socket, "presence-room", "bob").await;
+        join_presence(&mut sender, &sender_socket, "presence-room", "alice").await;
+        assert_eq!(recv_json(&mut receiver).await["event"], "pusher_internal:member_added");
+
+        sender.send_text(json!({"event": "client-hello", "channel": "presence-room", "data": {}}).to_string()).await;
+
+        let event = recv_json(&mut receiver).await;
+        assert_eq!(event["event"], "client-hello");
+        assert_eq!(event["user_id"], "alice");
+    }
+
+    #[tokio::test]
+    async fn client_events_are_rejected_when_disabled() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut sender, sender_socket) = connect(&pusher).await;
+        join(&mut sender, &sender_socket, "private-room").await;
+
+        sender.send_text(json!({"event": "client-typing", "channel": "private-room", "data": {}}).to_string()).await;
+
+        let error = recv_json(&mut sender).await;
+        assert_eq!(error["event"], "pusher:error");
+        assert_eq!(error["code"], 4301);
+    }
+
+    #[tokio::test]
+    async fn client_events_require_prefix_private_channel_and_subscription() {
+        let pusher = client_messages_app();
+        let (mut sender, sender_socket) = connect(&pusher).await;
+        let (mut other, other_socket) = connect(&pusher).await;
+        join(&mut sender, &sender_socket, "private-room").await;
+        join(&mut other, &other_socket, "private-other").await;
+        sender.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "public-room"}}).to_string()).await;
+        assert_eq!(recv_json(&mut sender).await["event"], "pusher_internal:subscription_succeeded");
+
+        sender.send_text(json!({"event": "typing", "channel": "private-room", "data": {}}).to_string()).await;
+        assert_eq!(recv_json(&mut sender).await["code"], 4301);
+
+        sender.send_text(json!({"event": "client-typing", "channel": "public-room", "data": {}}).to_string()).await;
+        assert_eq!(recv_json(&mut sender).await["code"], 4301);
+
+        sender.send_text(json!({"event": "client-typing", "channel": "private-other", "data": {}}).to_string()).await;
+        assert_eq!(recv_json(&mut sender).await["code"], 4301);
+
+        sender.send_text(json!({"event": "client-typing", "channel": "private-room", "data": "x".repeat(10 * 1024 + 1)}).to_string()).await;
+        assert_eq!(recv_json(&mut sender).await["code"], 4301);
+        assert_silent(&mut other).await;
+    }
+
+    #[tokio::test]
+    async fn connections_over_capacity_are_refused() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        pusher.set_capacity(3);
+        let handshakes = (0..10).map(|_| {
+            let pusher = pusher.clone();
+            tokio::spawn(async move {
+                let filter = warp::query().and(warp::ws()).and_then(move |query, w| ws(pusher.clone(), query, None, w));
+                let mut client = warp::test::ws().path(CONNECT_PATH).handshake(filter).await.expect("handshake");
+                let first = recv_json(&mut client).await;
+                (client, first)
+            })
+        });
+
+        let mut accepted = Vec::new();
+        for handshake in futures::future::join_all(handshakes).await {
+            let (mut client, first) = handshake.unwrap();
+            if first["event"] == "pusher:connection_established" {
+                accepted.push(client);
+                continue;
+            }
+            assert_eq!(first["event"], "pusher:error");
+            assert_eq!(first["code"], 4004);
+            client.recv_closed().await.unwrap();
+        }
+        assert_eq!(accepted.len(), 3);
+        assert_eq!(pusher.connections_count(), 3);
+
+        drop(accepted.pop());
+        while pusher.connections_count() > 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let (_client, _) = connect(&pusher).await;
+        assert_eq!(pusher.connections_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn origins_outside_allowed_list_are_refused() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        pusher.set_allowed_origins(vec!["https://*.example.com".to_owned()]);
+        let filter = {
+            let pusher = pusher.clone();
+            warp::query().and(warp::header::optional::<String>("origin")).and(warp::ws()).and_then(move |query, origin, w| ws(pusher.clone(), query, origin, w))
+        };
+
+        let mut allowed = warp::test::ws().path(CONNECT_PATH).header("origin", "https://app.example.com").handshake(filter.clone()).await.unwrap();
+        assert_eq!(recv_json(&mut allowed).await["event"], "pusher:connection_established");
+
+        for origin in [Some("https://example.org"), None] {
+            let request = warp::test::ws().path(CONNECT_PATH);
+            let request = match origin {
+                Some(origin) => request.header("origin", origin),
+                None => request,
+            };
+            let mut refused = request.handshake(filter.clone()).await.unwrap();
+            let error = recv_json(&mut refused).await;
+            assert_eq!(error["event"], "pusher:error");
+            assert_eq!(error["code"], 4009);
+            refused.recv_closed().await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_connections_are_pinged_then_closed() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        pusher.set_activity_timeout(10);
+        let (mut client, _) = connect(&pusher).await;
+
+        assert_eq!(recv_json(&mut client).await["event"], "pusher:ping");
+        assert_eq!(recv_json(&mut client).await["code"], 4201);
+        client.recv_closed().await.unwrap();
+        assert_eq!(pusher.connections_count(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pong_keeps_connection_alive() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        pusher.set_activity_timeout(10);
+        let (mut client, _) = connect(&pusher).await;
+
+        for _ in 0..3 {
+            assert_eq!(recv_json(&mut client).await["event"], "pusher:ping");
+            client.send_text(json!({"event": "pusher:pong", "data": {}}).to_string()).await;
+        }
+        client.send_text(json!({"event": "pusher:ping", "data": {}}).to_string()).await;
+        assert_eq!(recv_json(&mut client).await["event"], "pusher:pong");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn websocket_pings_count_as_activity() {
+        let mut pusher = Pusher::new(1, "key", "secret");
+        pusher.set_activity_timeout(10);
+        let (mut client, _) = connect(&pusher).await;
+
+        tokio::time::sleep(std::time::Duration::from_secs(8)).await;
+        client.send(warp::ws::Message::ping(Vec::new())).await;
+        assert!(client.recv().await.unwrap().is_pong());
+        tokio::time::sleep(std::time::Duration::from_secs(8)).await;
+        assert_silent(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_reported_without_closing() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut client, _) = connect(&pusher).await;
+
+        client.send_text("not json").await;
+        let error = recv_json(&mut client).await;
+        assert_eq!(error["event"], "pusher:error");
+        assert_eq!(error["code"], 4300);
+
+        client.send(warp::ws::Message::binary(vec![1, 2, 3])).await;
+        assert_eq!(recv_json(&mut client).await["code"], 4300);
+
+        client.send_text(json!({"event": "pusher:unsubscribe", "data": {"channel": "room"}}).to_string()).await;
+        assert_eq!(recv_json(&mut client).await["code"], 4300);
+
+        client.send_text(json!({"event": "pusher:ping", "data": {}}).to_string()).await;
+        assert_eq!(recv_json(&mut client).await["event"], "pusher:pong");
+    }
+
+    #[tokio::test]
+    async fn unsupported_protocol_versions_are_refused() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let filter = warp::query().and(warp::ws()).and_then(move |query, w| ws(pusher.clone(), query, None, w));
+
+        for (path, code) in [("/?protocol=4", 4007), ("/?protocol=8&client=js", 4007), ("/?protocol=seven", 4006), ("/?client=js", 4008)] {
+            let mut client = warp::test::ws().path(path).handshake(filter.clone()).await.unwrap();
+            let error = recv_json(&mut client).await;
+            assert_eq!(error["event"], "pusher:error");
+            assert_eq!(error["code"], code, "{}", path);
+            client.recv_closed().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_in_users_receive_server_to_user_events() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut laptop, laptop_socket) = connect(&pusher).await;
+        let (mut phone, phone_socket) = connect(&pusher).await;
+        let (mut other, other_socket) = connect(&pusher).await;
+
+        let success = signin(&mut laptop, &laptop_socket, "alice").await;
+        assert_eq!(success["event"], "pusher:signin_success");
+        let data: Value = serde_json::from_str(success["data"].as_str().unwrap()).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(data["user_data"].as_str().unwrap()).unwrap()["id"], "alice");
+        assert_eq!(signin(&mut phone, &phone_socket, "alice").await["event"], "pusher:signin_success");
+        assert_eq!(signin(&mut other, &other_socket, "bob").await["event"], "pusher:signin_success");
+
+        let request: EventRequestBody = serde_json::from_value(json!({"name": "notice", "channel": "#server-to-user-alice", "data": "{}"})).unwrap();
+        request.payload_publish(pusher.clone()).await.unwrap();
+
+        for client in [&mut laptop, &mut phone] {
+            let event = recv_json(client).await;
+            assert_eq!(event["event"], "notice");
+            assert_eq!(event["channel"], "#server-to-user-alice");
+        }
+        assert_silent(&mut other).await;
+    }
+
+    #[tokio::test]
+    async fn terminating_a_user_closes_all_of_its_sockets() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut laptop, laptop_socket) = connect(&pusher).await;
+        let (mut phone, phone_socket) = connect(&pusher).await;
+        let (mut other, other_socket) = connect(&pusher).await;
+        signin(&mut laptop, &laptop_socket, "alice").await;
+        signin(&mut phone, &phone_socket, "alice").await;
+        signin(&mut other, &other_socket, "bob").await;
+
+        pusher.terminate_user_connections("alice").await;
+
+        for client in [&mut laptop, &mut phone] {
+            assert_eq!(recv_json(client).await["code"], 4009);
+            client.recv_closed().await.unwrap();
+        }
+        assert_silent(&mut other).await;
+        while pusher.users.read().await.contains_key("alice") {
+            tokio::task::yield_now().await;
+        }
+        assert!(pusher.users.read().await.contains_key("bob"));
+    }
+
+    #[tokio::test]
+    async fn invalid_signin_closes_the_connection() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let user_data = json!({"id": "alice"}).to_string();
+
+        for auth in ["key:00", "malformed"] {
+            let (mut client, _) = connect(&pusher).await;
+            client.send_text(json!({"event": "pusher:signin", "data": {"auth": auth, "user_data": user_data}}).to_string()).await;
+
+            let error = recv_json(&mut client).await;
+            assert_eq!(error["event"], "pusher:error");
+            assert_eq!(error["code"], 4009);
+            client.recv_closed().await.unwrap();
+        }
+        assert!(pusher.users.read().await.is_empty());
+
+        let metrics = render_metrics([&pusher].into_iter()).await;
+        assert!(metrics.contains("pusher_auth_failures_total{app_id=\"1\",source=\"websocket\"} 2"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn encrypted_channels_require_auth_and_refuse_client_events() {
+        let pusher = client_messages_app();
+        let (mut client, socket_id) = connect(&pusher).await;
+
+        client.send_text(json!({"event": "pusher:subscribe", "data": {"channel": "private-encrypted-room"}}).to_string()).await;
+        assert_eq!(recv_json(&mut client).await["event"], "pusher:subscription_error");
+
+        join(&mut client, &socket_id, "private-encrypted-room").await;
+        client.send_text(json!({"event": "client-typing", "channel": "private-encrypted-room", "data": {}}).to_string()).await;
+        assert_eq!(recv_json(&mut client).await["code"], 4301);
+    }
+
+    #[tokio::test]
+    async fn cache_channels_replay_the_last_event() {
+        let pusher = Pusher::new(1, "key", "secret");
+        let (mut first, _) = connect(&pusher).await;
+        let subscribe = json!({"event": "pusher:subscribe", "data": {"channel": "cache-room"}}).to_string();
+
+        first.send_text(subscribe.clone()).await;
+        assert_eq!(recv_json(&mut first).await["event"], "pusher_internal:subscription_succeeded");
+        let miss = recv_json(&mut first).await;
+        assert_eq!(miss, json!({"event": "pusher:cache_miss", "channel": "cache-room"}));
+
+        let request: EventRequestBody = serde_json::from_value(json!({"name": "score", "channel": "cache-room", "data": "1-0"})).unwrap();
+        request.payload_publish(pusher.clone()).await.unwrap();
+        assert_eq!(recv_json(&mut first).await["event"], "score");
+
+        let (mut second, _) = connect(&pusher).await;
+        second.send_text(subscribe).await;
+        assert_eq!(recv_json(&mut second).await["event"], "pusher_internal:subscription_succeeded");
+        let replayed = recv_json(&mut second).await;
+        assert_eq!(replayed["event"], "score");
+        assert_eq!(replayed["channel"], "cache-room");
+    }
+}
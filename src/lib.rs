@@ -5,23 +5,58 @@ mod routes;
 mod handlers;
 
 use std::net::SocketAddr;
-use crate::app::{Pusher, PusherServer};
+use std::sync::Arc;
+use crate::app::PusherServer;
 
-const APPLICATION_NAME: &'static str = env!("CARGO_PKG_NAME");
+pub use crate::app::{Adapter, Config, ConfigError, ConfigFormat, MemoryAdapter, Pusher, RedisAdapter, SlowConsumerPolicy, Webhook};
 
-pub async fn start(app_id: &str, app_key: &str, app_secret: &str, bind_address: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    env_logger::init();
+const APPLICATION_NAME: &str = env!("CARGO_PKG_NAME");
 
-    let server: PusherServer = PusherServer::new(Pusher::new(app_id.parse::<u32>()?, app_key, app_secret));
-    let bind_address: SocketAddr = bind_address.parse().expect("BIND_ADDRESS is invalid");
-    let routes = routes::routes(server, &APPLICATION_NAME);
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+pub async fn start(app_id: &str, app_key: &str, app_secret: &str, bind_address: &str) -> Result<(), Error> {
+    let config = Config { apps: vec![Pusher::new(app_id.parse::<u32>()?, app_key, app_secret)], redis_url: None };
+
+    start_with(config, bind_address).await
+}
+
+/// Starts the server with every app described by a TOML, JSON or YAML configuration file.
+pub async fn start_with_config(config_path: &str, bind_address: &str) -> Result<(), Error> {
+    start_with(Config::from_file(config_path)?, bind_address).await
+}
+
+/// Starts the server, joining the other nodes through Redis when the config has a `redis_url`.
+pub async fn start_with(config: Config, bind_address: &str) -> Result<(), Error> {
+    let adapter: Option<Arc<dyn Adapter>> = match &config.redis_url {
+        Some(url) => Some(Arc::new(RedisAdapter::connect(url).await?)),
+        None => None,
+    };
+
+    serve(config, bind_address, adapter).await
+}
+
+/// Starts the server as one node of a cluster whose nodes share the adapter.
+pub async fn start_with_adapter(config: Config, bind_address: &str, adapter: Arc<dyn Adapter>) -> Result<(), Error> {
+    serve(config, bind_address, Some(adapter)).await
+}
+
+async fn serve(config: Config, bind_address: &str, adapter: Option<Arc<dyn Adapter>>) -> Result<(), Error> {
+    let _ = env_logger::try_init();
+
+    let mut server: PusherServer = PusherServer::try_from(config)?;
+    if let Some(adapter) = adapter {
+        server.join_cluster(adapter).await?;
+    }
+    server.watch_channels();
+    let bind_address: SocketAddr = bind_address.parse().map_err(|err| format!("BIND_ADDRESS {:?} is invalid: {}", bind_address, err))?;
+    let routes = routes::routes(server, APPLICATION_NAME);
+
+    let (bind_address, server) = warp::serve(routes).try_bind_ephemeral(bind_address)?;
 
     eprintln!("starting websocket server...");
     eprintln!("bind address: {}", &bind_address);
 
-    warp::serve(routes).run(bind_address).await;
+    server.await;
 
     Ok(())
 }
-
-
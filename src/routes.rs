@@ -1,20 +1,50 @@
 use warp::{Filter, Rejection};
 use warp::filters::BoxedFilter;
 use warp::hyper::Method;
+use warp::http::StatusCode;
+use warp::http::header::{HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN, VARY};
 use warp::path::FullPath;
-use crate::app::{PusherQuery, PusherServer, Pusher, EventRequestBody};
+use serde::de::DeserializeOwned;
+use warp::hyper::body::Bytes;
+use crate::app::{api_route, ConnectionQuery, PusherQuery, PusherServer, Pusher, EventRequestBody, BatchEventRequestBody, CustomError, MAX_BODY_SIZE};
 
 use crate::handlers;
 
 pub(crate) fn routes(server: PusherServer, app_name: &'static str) -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
-    index_filter()
+    let routes = index_filter()
         .or(health_filter())
-        .or(event_filter(&server).and_then(handlers::event_create).recover(handlers::handle_rejection))
-        .or(channel_filter(&server).and_then(handlers::get_channel).recover(handlers::handle_rejection))
-        .or(channels_filter(&server).and_then(handlers::list_channels).recover(handlers::handle_rejection))
-        .or(websocket_filter(&server).and_then(handlers::ws).recover(handlers::handle_rejection))
-        .with(warp::cors().allow_any_origin())
+        .or(metrics_filter(&server).and_then(handlers::metrics))
+        .or(preflight_filter())
+        .or(event_filter(&server).and_then(handlers::event_create))
+        .or(batch_event_filter(&server).and_then(handlers::batch_event_create))
+        .or(channel_filter(&server).and_then(handlers::get_channel))
+        .or(channel_users_filter(&server).and_then(handlers::get_channel_users))
+        .or(channels_filter(&server).and_then(handlers::list_channels))
+        .or(stats_filter(&server).and_then(handlers::get_stats))
+        .or(terminate_user_connections_filter(&server).and_then(handlers::terminate_user_connections))
+        .or(websocket_filter(&server).and_then(handlers::ws))
+        .or(unknown_app_websocket_filter(&server).and_then(handlers::ws_unknown_app))
+        .recover(handlers::handle_rejection);
+
+    let metrics_server = server.clone();
+    cors_origin(&server)
+        .and(routes)
+        .map(with_cors)
         .with(warp::log(app_name))
+        .with(warp::log::custom(move |info| record_api_request(&metrics_server, info)))
+}
+
+/// Counts every REST API response, rejections included, under the app it targets.
+/// Authentication failures are told apart by their status, whichever check rejected the request.
+fn record_api_request(server: &PusherServer, info: warp::log::Info) {
+    if let Some((app_id, route)) = api_route(info.path()) {
+        if let Ok(pusher) = server.find_by_id(app_id) {
+            pusher.metrics.api_request(route, info.status().as_u16());
+            if info.status() == StatusCode::UNAUTHORIZED {
+                pusher.metrics.auth_failed("api");
+            }
+        }
+    }
 }
 
 #[inline(always)]
@@ -23,23 +53,76 @@ pub(crate) fn index_filter() -> impl Filter<Extract = impl warp::Reply, Error =
 }
 
 #[inline(always)]
-pub(crate) fn websocket_filter(server: &PusherServer) -> BoxedFilter<(Pusher, warp::filters::ws::Ws)> {
-    validate_app_by_key(server).and(warp::ws()).boxed()
+pub(crate) fn websocket_filter(server: &PusherServer) -> BoxedFilter<(Pusher, ConnectionQuery, Option<String>, warp::filters::ws::Ws)> {
+    validate_app_by_key(server).and(warp::query::<ConnectionQuery>()).and(warp::header::optional::<String>("origin")).and(warp::ws()).boxed()
+}
+
+/// Websocket upgrades for an app key no app uses, answered with a `pusher:error` instead of a 404.
+#[inline(always)]
+pub(crate) fn unknown_app_websocket_filter(server: &PusherServer) -> BoxedFilter<(warp::filters::ws::Ws, )> {
+    warp::path!("app" / String)
+        .and(with_pusher_server(server.clone()))
+        .and_then(|app_key: String, server: PusherServer| async move {
+            match server.find(app_key.as_str()) {
+                Ok(_) => Err(warp::reject::not_found()),
+                Err(_) => Ok(()),
+            }
+        })
+        .untuple_one()
+        .and(warp::ws())
+        .boxed()
 }
 
 #[inline(always)]
 pub(crate) fn event_filter(server: &PusherServer) -> BoxedFilter<(Pusher, PusherQuery, EventRequestBody)> {
-    validate_app_by_id(server).and(warp::path!("events")).and(warp::path::end()).and(warp::post()).and(json_body()).boxed()
+    apps().and(warp::path!("events")).and(warp::post()).and(validate_app_by_id(server)).and(json_body()).and_then(verify_json_body).untuple_one().boxed()
+}
+
+#[inline(always)]
+pub(crate) fn batch_event_filter(server: &PusherServer) -> BoxedFilter<(Pusher, PusherQuery, BatchEventRequestBody)> {
+    apps().and(warp::path!("batch_events")).and(warp::post()).and(validate_app_by_id(server)).and(json_body()).and_then(verify_json_body).untuple_one().boxed()
 }
 
 #[inline(always)]
 pub(crate) fn channels_filter(server: &PusherServer) -> BoxedFilter<(Pusher, PusherQuery)> {
-    validate_app_by_id(server).and(warp::path!("channels")).and(warp::path::end()).and(warp::get()).boxed()
+    apps().and(warp::path!("channels")).and(warp::get()).and(validate_app_by_id(server)).boxed()
 }
 
 #[inline(always)]
 pub(crate) fn channel_filter(server: &PusherServer) -> BoxedFilter<(Pusher, PusherQuery, String)> {
-    validate_app_by_id(server).and(warp::path!("channels" / String)).and(warp::path::end()).and(warp::get()).boxed()
+    apps().and(warp::path!("channels" / String)).and(warp::get()).and(validate_app_by_id(server))
+        .map(|channel_name, pusher, query| (pusher, query, channel_name)).untuple_one().boxed()
+}
+
+#[inline(always)]
+pub(crate) fn channel_users_filter(server: &PusherServer) -> BoxedFilter<(Pusher, PusherQuery, String)> {
+    apps().and(warp::path!("channels" / String / "users")).and(warp::get()).and(validate_app_by_id(server))
+        .map(|channel_name, pusher, query| (pusher, query, channel_name)).untuple_one().boxed()
+}
+
+#[inline(always)]
+pub(crate) fn stats_filter(server: &PusherServer) -> BoxedFilter<(Pusher, PusherQuery)> {
+    apps().and(warp::path!("stats")).and(warp::get()).and(validate_app_by_id(server)).boxed()
+}
+
+#[inline(always)]
+pub(crate) fn terminate_user_connections_filter(server: &PusherServer) -> BoxedFilter<(Pusher, PusherQuery, String)> {
+    apps()
+        .and(warp::path!("users" / String / "terminate_connections"))
+        .and(warp::post())
+        .and(validate_app_by_id(server))
+        .and(json_body())
+        .and_then(|user_id: String, pusher: Pusher, query: PusherQuery, body: Bytes| async move {
+            pusher.ensure_valid_body(&query, &body)?;
+            Ok::<_, Rejection>((pusher, query, user_id))
+        })
+        .untuple_one()
+        .boxed()
+}
+
+#[inline(always)]
+pub(crate) fn metrics_filter(server: &PusherServer) -> BoxedFilter<(PusherServer, )> {
+    warp::path!("metrics").and(warp::get()).and(with_pusher_server(server.clone())).boxed()
 }
 
 #[inline(always)]
@@ -48,14 +131,58 @@ pub(crate) fn health_filter() -> impl Filter<Extract = impl warp::Reply, Error =
 }
 
 
+#[inline(always)]
+pub(crate) fn preflight_filter() -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
+    // Matches the method by hand: `warp::options()` would turn every other request
+    // under /apps into a 405 instead of letting the API routes report their own errors.
+    warp::path("apps").and(warp::method()).and_then(|method: Method| async move {
+        if method != Method::OPTIONS {
+            return Err(warp::reject::not_found());
+        }
+        let reply = warp::reply::with_header(StatusCode::NO_CONTENT, "access-control-allow-methods", "GET, POST, OPTIONS");
+        Ok(warp::reply::with_header(reply, "access-control-allow-headers", "content-type"))
+    })
+}
+
+/// The `Origin` of a REST API request when the app it targets allows it, echoed back
+/// as `Access-Control-Allow-Origin` by [`with_cors`].
+#[inline(always)]
+fn cors_origin(server: &PusherServer) -> impl Filter<Extract = (Option<String>, ), Error = std::convert::Infallible> + Clone {
+    warp::path::full()
+        .and(warp::header::headers_cloned())
+        .and(with_pusher_server(server.clone()))
+        .map(|path: FullPath, headers: HeaderMap, server: PusherServer| {
+            let app_id = path.as_str().strip_prefix("/apps/")?.split('/').next()?.parse::<u32>().ok()?;
+            let pusher = server.find_by_id(app_id).ok()?;
+            let origin = headers.get(ORIGIN)?.to_str().ok()?;
+            pusher.is_origin_allowed(Some(origin)).then(|| origin.to_owned())
+        })
+}
+
+fn with_cors(origin: Option<String>, reply: impl warp::Reply) -> warp::reply::Response {
+    let mut response = reply.into_response();
+    if let Some(origin) = origin.and_then(|origin| HeaderValue::from_str(&origin).ok()) {
+        response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        response.headers_mut().insert(VARY, HeaderValue::from_static("origin"));
+    }
+    response
+}
+
 #[inline(always)]
 pub(crate) fn with_pusher_server(server: PusherServer) -> impl Filter<Extract = (PusherServer, ), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || server.clone())
 }
 
 #[inline(always)]
-pub(crate) fn json_body() -> impl Filter<Extract = (EventRequestBody, ), Error = Rejection> + Clone {
-    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+pub(crate) fn json_body() -> impl Filter<Extract = (Bytes, ), Error = Rejection> + Clone {
+    warp::body::content_length_limit(MAX_BODY_SIZE as u64).and(warp::body::bytes())
+}
+
+/// Checks the raw body against the signed `body_md5` before deserializing it.
+async fn verify_json_body<T: DeserializeOwned>(pusher: Pusher, query: PusherQuery, body: Bytes) -> Result<(Pusher, PusherQuery, T), Rejection> {
+    pusher.ensure_valid_body(&query, &body)?;
+    let body = serde_json::from_slice(&body).map_err(|_| warp::reject::custom(CustomError::InvalidBody))?;
+    Ok((pusher, query, body))
 }
 
 #[inline(always)]
@@ -63,30 +190,192 @@ pub(crate) fn path() -> impl Filter<Extract = (String, ), Error = std::convert::
     warp::path::full().and(warp::method()).map(|path: FullPath, method: Method| format!("{}\n{}\n", method, path.as_str()))
 }
 
+#[inline(always)]
+fn signed_query() -> impl Filter<Extract = (PusherQuery, ), Error = Rejection> + Clone {
+    warp::query::<PusherQuery>()
+        .and(warp::query::<Vec<(String, String)>>())
+        .map(|query: PusherQuery, params: Vec<(String, String)>| PusherQuery { params, ..query })
+}
+
 #[inline(always)]
 fn validate_app_by_key(server: &PusherServer) -> impl Filter<Extract = (Pusher, ), Error = Rejection> + Clone {
     warp::path!("app" / String)
-        .and(warp::query::<PusherQuery>())
         .and(with_pusher_server(server.clone()))
-        .and(path())
-        .and_then(|app_key: String, query: PusherQuery, server: PusherServer, path: String| async move {
-            match server.find(app_key.as_str()).unwrap_or_default().ensure_valid_signature(&query, path.as_str()) {
-                Ok(pusher) => Ok(pusher),
-                Err(e) => Err(e)
-            }
-        })
+        .and_then(|app_key: String, server: PusherServer| async move { server.find(app_key.as_str()) })
+}
+
+/// The `/apps/{id}` prefix of the REST API routes, whose app [`validate_app_by_id`] resolves.
+#[inline(always)]
+fn apps() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path!("apps" / u32 / ..).map(|_| ()).untuple_one()
 }
 
+/// The app a REST API request targets, once its signature is verified. Comes after the path
+/// and method of a route, so that a request is verified once, by the route serving it.
 #[inline(always)]
 fn validate_app_by_id(server: &PusherServer) -> impl Filter<Extract = (Pusher, PusherQuery, ), Error = Rejection> + Clone {
-    warp::path!("apps" / u32 / .. )
-        .and(warp::query::<PusherQuery>())
+    warp::path::full()
         .and(with_pusher_server(server.clone()))
+        .and_then(|path: FullPath, server: PusherServer| async move {
+            let (app_id, _) = api_route(path.as_str()).ok_or_else(warp::reject::not_found)?;
+            server.find_by_id(app_id)
+        })
+        .and(signed_query())
         .and(path())
-        .and_then(|app_id: u32, query: PusherQuery, server: PusherServer, path: String| async move {
-            match server.find_by_id(app_id).unwrap_or_default().ensure_valid_signature(&query, path.as_str()) {
-                Ok(pusher) => Ok((pusher, query)),
-                Err(e) => Err(e)
-            }
+        .and_then(|pusher: Pusher, query: PusherQuery, path: String| async move {
+            pusher.ensure_valid_signature(&query, path.as_str()).map(|pusher| (pusher, query))
         }).untuple_one()
 }
+
+#[cfg(test)]
+mod tests {
+    use warp::http::StatusCode;
+    use crate::app::{create_auth_signature, create_body_md5, Pusher, PusherServer};
+    use crate::routes::routes;
+
+    fn signed_uri(method: &str, path: &str, body: &str) -> String {
+        let mut params = vec![
+            "auth_key=key".to_owned(),
+            format!("auth_timestamp={}", chrono::Utc::now().timestamp()),
+            "auth_version=1.0".to_owned(),
+        ];
+        if !body.is_empty() {
+            params.push(format!("body_md5={}", create_body_md5(body.as_bytes())));
+        }
+        let query = params.join("&");
+        let signature = create_auth_signature(&format!("{}\n{}\n{}", method, path, query), "secret");
+        format!("{}?{}&auth_signature={}", path, query, signature)
+    }
+
+    #[tokio::test]
+    async fn signed_requests_reach_every_api_route() {
+        let filter = routes(PusherServer::new(Pusher::new(1, "key", "secret")), "test");
+        let event = r#"{"name":"e","channel":"room","data":"{}"}"#;
+        let batch = r#"{"batch":[{"name":"e","channel":"room","data":"{}"}]}"#;
+
+        let response = warp::test::request().method("POST").path(&signed_uri("POST", "/apps/1/events", event)).body(event).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request().method("POST").path(&signed_uri("POST", "/apps/1/batch_events", batch)).body(batch).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request().method("GET").path(&signed_uri("GET", "/apps/1/channels", "")).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request().method("POST").path(&signed_uri("POST", "/apps/1/users/alice/terminate_connections", "{}")).body("{}").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request().method("GET").path(&signed_uri("GET", "/apps/1/channels/presence-room/users", "")).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), br#"{"users":[]}"#);
+
+        let response = warp::test::request().method("GET").path(&signed_uri("GET", "/apps/1/stats", "")).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), br#"{"connections":0,"channels":0,"subscriptions":0}"#);
+    }
+
+    #[tokio::test]
+    async fn metrics_count_api_requests_and_auth_failures() {
+        let filter = routes(PusherServer::new(Pusher::new(1, "key", "secret")), "test");
+
+        let response = warp::test::request().method("GET").path(&signed_uri("GET", "/apps/1/channels/room", "")).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = warp::test::request().method("GET").path("/apps/1/stats?auth_key=key&auth_timestamp=0&auth_version=1.0&auth_signature=x").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = warp::test::request().method("GET").path("/metrics").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/plain; version=0.0.4");
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("pusher_api_requests_total{app_id=\"1\",route=\"channel\",status=\"200\"} 1"), "{}", body);
+        assert!(body.contains("pusher_api_requests_total{app_id=\"1\",route=\"stats\",status=\"401\"} 1"), "{}", body);
+        assert!(body.contains("pusher_auth_failures_total{app_id=\"1\",source=\"api\"} 1"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn tampered_body_is_rejected() {
+        let filter = routes(PusherServer::new(Pusher::new(1, "key", "secret")), "test");
+        let signed = r#"{"name":"e","channel":"room","data":"{}"}"#;
+        let sent = r#"{"name":"e","channel":"other","data":"{}"}"#;
+
+        let response = warp::test::request().method("POST").path(&signed_uri("POST", "/apps/1/events", signed)).body(sent).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = warp::test::request().method("POST").path("/apps/1/events?auth_key=key").body(sent).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn unknown_app_ids_are_not_found_before_any_signature_check() {
+        let filter = routes(PusherServer::new(Pusher::new(1, "key", "secret")), "test");
+        let event = r#"{"name":"e","channel":"room","data":"{}"}"#;
+
+        let response = warp::test::request().method("POST").path(&signed_uri("POST", "/apps/2/events", event)).body(event).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = warp::test::request().method("GET").path("/apps/2/channels").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn signatures_are_checked_by_the_matched_route_only() {
+        let filter = routes(PusherServer::new(Pusher::new(1, "key", "secret")), "test");
+
+        let query = format!("auth_key=key&auth_timestamp={}&auth_version=1.0&auth_signature=bad", chrono::Utc::now().timestamp());
+
+        let response = warp::test::request().method("GET").path(&format!("/apps/1/events?{}", query)).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let response = warp::test::request().method("GET").path(&format!("/apps/1/channels/room?{}", query)).reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn cors_follows_app_allowed_origins() {
+        let mut open = Pusher::new(1, "key", "secret");
+        open.set_allowed_origins(Vec::new());
+        let mut restricted = Pusher::new(2, "other-key", "secret");
+        restricted.set_allowed_origins(vec!["https://*.example.com".to_owned()]);
+        let mut server = PusherServer::new(open);
+        server.add(restricted);
+        let filter = routes(server, "test");
+
+        let preflight = |path: &'static str, origin: &'static str| {
+            warp::test::request().method("OPTIONS").path(path).header("origin", origin).reply(&filter)
+        };
+
+        let response = preflight("/apps/1/events", "https://anywhere.io").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://anywhere.io");
+
+        let response = preflight("/apps/2/events", "https://app.example.com").await;
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://app.example.com");
+
+        let response = preflight("/apps/2/events", "https://anywhere.io").await;
+        assert!(response.headers().get("access-control-allow-origin").is_none());
+
+        let response = warp::test::request().method("GET").path("/apps/2/channels").header("origin", "https://app.example.com").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["access-control-allow-origin"], "https://app.example.com");
+    }
+
+    #[tokio::test]
+    async fn websockets_for_unknown_apps_get_a_protocol_error() {
+        let filter = routes(PusherServer::new(Pusher::new(1, "key", "secret")), "test");
+
+        let mut client = warp::test::ws().path("/app/missing").handshake(filter).await.unwrap();
+        let error: serde_json::Value = serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(error["event"], "pusher:error");
+        assert_eq!(error["code"], 4001);
+        client.recv_closed().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn websockets_connect_without_signature() {
+        let filter = routes(PusherServer::new(Pusher::new(1, "key", "secret")), "test");
+
+        let mut client = warp::test::ws().path("/app/key?protocol=7&client=js&version=8.4.0&flash=false").handshake(filter).await.unwrap();
+        let established: serde_json::Value = serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap();
+        assert_eq!(established["event"], "pusher:connection_established");
+    }
+}